@@ -0,0 +1,16 @@
+// Shows go-live notifications pushed by ovenauth
+self.addEventListener('push', (event) => {
+  const data = event.data ? event.data.json() : {};
+  event.waitUntil(self.registration.showNotification(data.title ?? 'Stream is live', {
+    tag: data.tag,
+    data: { url: data.url },
+  }));
+});
+
+self.addEventListener('notificationclick', (event) => {
+  event.notification.close();
+  const url = event.notification.data?.url;
+  if (url) {
+    event.waitUntil(clients.openWindow(url));
+  }
+});
@@ -1,9 +1,10 @@
 import { Navigate } from "@solidjs/router";
-import { Component, createMemo, createResource, createSignal, Show } from "solid-js";
+import { Component, createMemo, createResource, createSignal, For, Show } from "solid-js";
 import { useService } from "solid-services";
 import Layout from "./Layout";
 import { AuthService } from "./store/AuthService";
 import Title from "./Title";
+import { OfflineChat, SinkKind } from "./types/user.interface";
 
 const Dashboard: Component = () => {
 
@@ -20,6 +21,54 @@ const Dashboard: Component = () => {
     authService().client.common.reset()
       .then(refetch);
   
+  const stop_stream = () => {
+    if (!confirm('Stop the live stream and reset the stream token?')) return;
+    authService().client.common.stop_stream()
+      .then(({ options }) => mutate(options));
+  }
+
+  const [recordings, { refetch: refetchRecordings }] = createResource(() => {
+    return authService().client.common.recordings();
+  });
+  const recording = () => recordings()?.some(r => r.state !== 'stopped' && r.state !== 'error');
+  const toggle_recording = () =>
+    (recording() ? authService().client.common.stop_recording() : authService().client.common.start_recording())
+      .catch(e => alert(e.message))
+      .then(refetchRecordings);
+
+  const [sinks, { refetch: refetchSinks }] = createResource(() => {
+    return authService().client.common.sinks();
+  });
+  const add_sink = () =>
+    authService().client.common.add_sink(sink_kind_input.value as SinkKind, sink_url_input.value.trim())
+      .then(() => { sink_url_input.value = ''; })
+      .catch(e => alert(e.message))
+      .then(refetchSinks);
+  const delete_sink = (id: number) => authService().client.common.delete_sink(id).then(refetchSinks);
+
+  const [pushSubscription, { mutate: setPushSubscription }] = createResource(async () => {
+    if (!('serviceWorker' in navigator)) return null;
+    const registration = await navigator.serviceWorker.register('/sw.js');
+    return registration.pushManager.getSubscription();
+  });
+  const toggle_push = async () => {
+    const common = authService().client.common;
+    const current = pushSubscription();
+    if (current) {
+      await common.push_unsubscribe(current.endpoint);
+      await current.unsubscribe();
+      setPushSubscription(null);
+      return;
+    }
+    const registration = await navigator.serviceWorker.ready;
+    const subscription = await registration.pushManager.subscribe({
+      userVisibleOnly: true,
+      applicationServerKey: await common.push_key(),
+    });
+    await common.push_subscribe(subscription.toJSON());
+    setPushSubscription(subscription);
+  };
+
   const [emoteIdLoading, setEmoteIdLoading] = createSignal(false);
   const update_emote_id = async () => {
     setEmoteIdLoading(true);
@@ -29,6 +78,8 @@ const Dashboard: Component = () => {
 
   const set_visibility = (e: Event) => authService().client.common.set_public((e.currentTarget as HTMLInputElement).checked).then(mutate);
   const update_title = () => authService().client.common.set_name(title_input.value.trim()).then(mutate);
+  const set_record = (e: Event) => authService().client.common.set_record((e.currentTarget as HTMLInputElement).checked).then(mutate);
+  const set_offline_chat = (e: Event) => authService().client.common.set_offline_chat((e.currentTarget as HTMLSelectElement).value as OfflineChat).then(mutate);
 
   const visibleicon = (
     <svg xmlns="http://www.w3.org/2000/svg" height="24px" viewBox="0 0 24 24" width="24px" fill="#000000">
@@ -51,6 +102,8 @@ const Dashboard: Component = () => {
   let tokeninput: HTMLInputElement;
   let emote_id_input: HTMLInputElement;
   let title_input: HTMLInputElement;
+  let sink_kind_input: HTMLSelectElement;
+  let sink_url_input: HTMLInputElement;
 
   const copy = () => {
     navigator.clipboard.writeText(tokeninput.value);
@@ -73,6 +126,11 @@ const Dashboard: Component = () => {
             <button type="button" onclick={reset} class="join-item btn btn-primary">{options() ? 'reset' : 'create'}</button>
           </div>
 
+          <h3 class="text-xl py-4">Stop Stream</h3>
+          <div>
+            <button type="button" onclick={stop_stream} class="btn btn-error">Stop and reset token</button>
+          </div>
+
           <h3 class="text-xl py-4">7TV.APP Emote Set ID</h3>
           <div class="join">
             <input ref={emote_id_input} class="input input-bordered join-item box-content" placeholder="7TV Emoteset ID" value={options()?.emote_id ?? ''} />
@@ -87,7 +145,74 @@ const Dashboard: Component = () => {
 
           <h3 class="text-xl py-4">Public?</h3>
           <input type="checkbox" class="toggle toggle-primary toggle-lg" onchange={set_visibility} checked={options()?.public ?? true} />
+
+          <h3 class="text-xl py-4">Chat while offline</h3>
+          <select class="select select-bordered" onchange={set_offline_chat} value={options()?.offline_chat ?? 'open'}>
+            <option value="open">Everyone</option>
+            <option value="followers">Followers only</option>
+            <option value="readonly">Read-only</option>
+          </select>
+
+          <h3 class="text-xl py-4">Record by default?</h3>
+          <input type="checkbox" class="toggle toggle-primary toggle-lg" onchange={set_record} checked={options()?.record ?? false} />
+
+          <h3 class="text-xl py-4">Recording</h3>
+          <div>
+            <button type="button" onclick={toggle_recording} class="btn btn-primary">{recording() ? 'Stop recording' : 'Start recording'}</button>
+          </div>
+
+          <h3 class="text-xl py-4">Browser notifications for followed streams</h3>
+          <div>
+            <button type="button" onclick={toggle_push} disabled={pushSubscription.loading || !('serviceWorker' in navigator)} class="btn btn-primary">
+              {pushSubscription() ? 'Disable' : 'Enable'}
+            </button>
+          </div>
+
+          <h3 class="text-xl py-4">Go-live notifications</h3>
+          <div class="flex flex-col gap-1">
+            <For each={sinks()}>
+              {s => (
+                <div class="join">
+                  <span class="join-item btn btn-disabled">{s.kind}</span>
+                  <input class="input input-bordered join-item box-content w-full font-mono" readonly value={s.url} />
+                  <button type="button" onclick={() => delete_sink(s.id)} class="join-item btn btn-error">Remove</button>
+                </div>
+              )}
+            </For>
+            <div class="join">
+              <select ref={sink_kind_input} class="select select-bordered join-item">
+                <option value="discord">Discord</option>
+                <option value="webhook">Webhook</option>
+              </select>
+              <input ref={sink_url_input} class="input input-bordered join-item box-content w-full" placeholder="Webhook URL" />
+              <button type="button" onclick={add_sink} class="join-item btn btn-primary">Add</button>
+            </div>
+          </div>
         </div>
+
+        <Show when={recordings()?.length}>
+          <div class="rounded-box p-4 mt-4 shadow bg-base-200 overflow-x-auto">
+            <h3 class="text-xl py-4">Recordings</h3>
+            <table class="table">
+              <thead>
+                <tr><th>Started</th><th>State</th><th>Length</th><th>Size</th><th>File</th></tr>
+              </thead>
+              <tbody>
+                <For each={recordings()}>
+                  {r => (
+                    <tr>
+                      <td>{new Date(r.started_at ?? r.created_at).toLocaleString()}</td>
+                      <td>{r.state}</td>
+                      <td>{r.duration ? `${Math.round(r.duration / 60000)} min` : '-'}</td>
+                      <td>{r.bytes ? `${(r.bytes / 1e6).toFixed(1)} MB` : '-'}</td>
+                      <td class="font-mono">{r.file_path ?? '-'}</td>
+                    </tr>
+                  )}
+                </For>
+              </tbody>
+            </table>
+          </div>
+        </Show>
       </Layout>
     </>
   );
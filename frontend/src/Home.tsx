@@ -1,29 +1,37 @@
 import { Link } from "@solidjs/router";
-import { Component, For } from "solid-js";
+import { Component, createResource, For, onCleanup, onMount } from "solid-js";
 import { useService } from "solid-services";
 import Layout from "./Layout";
 import { AuthService } from "./store/AuthService";
 import Thumbnail from "./Thumbnail";
 import Title from "./Title";
-import ViewCount from "./ViewCount";
 
 const Home: Component = () => {
     const authService = useService(AuthService);
     let t: HTMLElement;
 
+    const [streams, { refetch }] = createResource(() => authService().client.common.streams());
+
+    onMount(() => {
+        const interval = setInterval(() => refetch(), 10000);
+        onCleanup(() => clearInterval(interval));
+    });
+
+    const viewers = (count: number) => count === 1 ? count + ' Viewer' : count + ' Viewers';
+
     return <>
         <Title value="Home" />
         <Layout>
             <div class="grid xl:grid-cols-4 lg:grid-cols-3 md:grid-cols-2 grid-cols-1 gap-4">
-                <For each={authService().users}>
-                    {(user) =>
+                <For each={streams.latest} fallback={<h3 class="text-xl">Nobody is live right now</h3>}>
+                    {(stream) =>
                         <div ref={(e) => t = e} class="aspect-video card shadow-xl card-bordered image-full">
-                            <Thumbnail hover={t} interval={10000} name={user.username}></Thumbnail>
+                            <Thumbnail hover={t} interval={10000} name={stream.username}></Thumbnail>
                             <div class="justify-end card-body">
-                                <h2 class="card-title">{user.username}</h2>
-                                <ViewCount interval={10000} name={user.username}></ViewCount>
+                                <h2 class="card-title">{stream.name || stream.username}</h2>
+                                <h5>{stream.username} · {viewers(stream.viewers)}</h5>
                                 <div class="card-actions">
-                                    <Link href={`/${user.username}`} class="btn btn-primary">Watch</Link>
+                                    <Link href={`/${stream.username}`} class="btn btn-primary">Watch</Link>
                                 </div>
                             </div>
                         </div>
@@ -3,6 +3,7 @@ import { createResource } from "solid-js";
 export type PublicStreamOptions = {
   name?: string,
   emote_id?: string,
+  followers?: number,
 };
 
 type Owner = {
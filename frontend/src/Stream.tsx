@@ -1,10 +1,12 @@
 import { useParams, useRouteData, useSearchParams } from "@solidjs/router";
-import { Component, Show, Suspense, useContext } from "solid-js";
+import { Component, createResource, createSignal, Show, Suspense, useContext } from "solid-js";
+import { useService } from "solid-services";
 import Title from "./Title";
 import player from "./Player";
 import Chat from "./chat/Chat";
 import StreamData from "./Stream.data";
 import { TheaterContext } from "./store/shownav";
+import { AuthService } from "./store/AuthService";
 player;
 
 const Stream: Component = () => {
@@ -13,6 +15,16 @@ const Stream: Component = () => {
     const { streamInfo: data } = useRouteData<typeof StreamData>();
 
     const [theater] = useContext(TheaterContext);
+    const authService = useService(AuthService);
+    const [follows, { refetch: refetchFollows }] = createResource(() => authService().user, () => authService().client.common.follows());
+    const following = () => follows()?.some(f => f.username === params.user);
+    const [followers, setFollowers] = createSignal<number>();
+    const toggleFollow = async () => {
+        const common = authService().client.common;
+        const now = following() ? await common.unfollow(params.user) : await common.follow(params.user);
+        setFollowers((followers() ?? data.latest.followers ?? 0) + (now ? 1 : -1));
+        refetchFollows();
+    };
     const sidebaropen = () => !search.sidebar;
     const setSidebaropen = (value: boolean) => setSearch({ sidebar: value ? '' : '1' });
     const showIcon = (
@@ -47,7 +59,13 @@ const Stream: Component = () => {
                                     <span class="text-3xl">{params.user.substring(0, 2)}</span>
                                 </div>
                             </div>
-                            <h1 class="p-8 text-xl">{data.latest.name ?? 'No Stream title'}</h1>
+                            <div class="p-8">
+                                <h1 class="text-xl">{data.latest.name ?? 'No Stream title'}</h1>
+                                <span class="text-sm opacity-70">{followers() ?? data.latest.followers ?? 0} followers</span>
+                            </div>
+                            <Show when={authService().user && authService().user.username !== params.user}>
+                                <button type="button" class="btn btn-primary self-center" onclick={toggleFollow}>{following() ? 'Unfollow' : 'Follow'}</button>
+                            </Show>
                         </div>
                     </div>
                 </Show>
@@ -4,7 +4,8 @@ import { StatService } from "./store/StatService";
 
 interface ViewCountProps {
     name: string;
-    interval?: number | boolean;
+    /** Follow viewer count changes as they happen */
+    live?: boolean;
 }
 
 const ViewCount: Component<ViewCountProps> = (props) => {
@@ -12,7 +13,7 @@ const ViewCount: Component<ViewCountProps> = (props) => {
 
     const fetcher = (name: string) => statService().getViewers(name);
 
-    const [vc, { refetch }] = createResource(() => props.name, fetcher);
+    const [vc, { refetch, mutate }] = createResource(() => props.name, fetcher);
 
     const viewers = () => {
         let count = vc.latest;
@@ -20,9 +21,12 @@ const ViewCount: Component<ViewCountProps> = (props) => {
     };
 
     onMount(() => {
-        if (props.interval !== false && typeof props.interval === 'number') {
-            const interval = setInterval(() => refetch(), props.interval);
-            onCleanup(() => clearInterval(interval));
+        if (props.live) {
+            const events = statService().streamEvents(props.name);
+            events.addEventListener('viewers', (e: MessageEvent) => mutate(JSON.parse(e.data).viewers));
+            events.addEventListener('online', () => refetch());
+            events.addEventListener('offline', () => mutate(-1));
+            onCleanup(() => events.close());
         }
     });
 
@@ -3,9 +3,8 @@ import { createStore, produce } from 'solid-js/store';
 import { useNavigate, useParams, useLocation } from '@solidjs/router';
 import { useService } from 'solid-services';
 import { AuthService } from '../store/AuthService';
-import type { IncomingMessage, MessagePosition } from './ChatMessage';
+import type { EmotePosition, IncomingMessage, MessagePosition } from './ChatMessage';
 import ChatMessage from './ChatMessage';
-import { IUser } from '../types/user.interface';
 import color from '../utils/colors';
 import { TheaterContext } from '../store/shownav';
 
@@ -28,7 +27,49 @@ export type MsgMessage = {
     data: IncomingMessage,
 }
 
-export type Message = JoinMessage | LeaveMessage | ConnectMessage | MsgMessage;
+export type DeleteMessage = {
+    type: "delete",
+    data: string,
+};
+
+export type EditMessage = {
+    type: "edit",
+    data: { message_id: string, content: string, emotes: EmotePosition[] },
+};
+
+export type ClearMessage = {
+    type: "clear",
+};
+
+export type ErrorMessage = {
+    type: "error",
+    data: { code: string, message: string, ref?: string },
+};
+
+export type ResyncMessage = {
+    type: "resync",
+    data: IncomingMessage[],
+};
+
+export type PresenceMessage = {
+    type: "presence",
+    data: { total: number, authenticated: number, anonymous: number },
+};
+
+export type StreamStateMessage = {
+    type: "stream_online" | "stream_offline",
+};
+
+// The server is shutting down, reconnect within a random part of within_ms
+export type ReconnectMessage = {
+    type: "reconnect",
+    data: { within_ms: number },
+};
+
+export type Message = JoinMessage | LeaveMessage | ConnectMessage | MsgMessage | DeleteMessage | EditMessage | ClearMessage | ErrorMessage | ResyncMessage | PresenceMessage | StreamStateMessage | ReconnectMessage;
+
+// Chat protocol version spoken by this client, see src/chat/protocol.rs
+const PROTOCOL_VERSION = 1;
 
 const Chat: Component<{ toggleSidebar?: () => void }> = (props) => {
     const authService = useService(AuthService);
@@ -38,12 +79,16 @@ const Chat: Component<{ toggleSidebar?: () => void }> = (props) => {
 
     const [chatState, setChatState] = createStore<IncomingMessage[]>([]);
     const [roomState, setRoomState] = createStore<string[]>([]);
+    const [presence, setPresence] = createSignal<PresenceMessage['data']>();
+    const [chatError, setChatError] = createSignal<string>();
     const [loading, setLoading] = createSignal(true);
 
     const [theater] = useContext(TheaterContext);
 
     const [ws, setWs] = createSignal<WebSocket>();
+    const [reconnects, setReconnects] = createSignal(0);
     createEffect(() => {
+        reconnects();
         if (!params.user) {
             navigate('/');
             return;
@@ -72,10 +117,36 @@ const Chat: Component<{ toggleSidebar?: () => void }> = (props) => {
             } else if (msg.type === 'msg') {
                 // This order because we flip with flex direction reverse
                 setChatState(cs => [msg.data, ...cs]);
+            } else if (msg.type === 'delete') {
+                setChatState(cs => cs.filter(m => m.message_id !== msg.data));
+            } else if (msg.type === 'edit') {
+                setChatState(m => m.message_id === msg.data.message_id, { content: msg.data.content, emotes: msg.data.emotes });
+            } else if (msg.type === 'clear') {
+                setChatState([]);
+            } else if (msg.type === 'presence') {
+                setPresence(msg.data);
+            } else if (msg.type === 'resync') {
+                setChatState([...msg.data].reverse());
+            } else if (msg.type === 'stream_online') {
+                setChatError(undefined);
+            } else if (msg.type === 'reconnect') {
+                setTimeout(() => {
+                    // The history is sent again on connect
+                    setChatState([]);
+                    setReconnects(r => r + 1);
+                }, Math.random() * msg.data.within_ms);
+            } else if (msg.type === 'error') {
+                console.warn(msg.data.code, msg.data.message);
+                if (msg.data.code === 'forbidden') {
+                    setChatError(msg.data.message);
+                }
             }
         };
         ws.onerror = (e) => console.log(e);
-        ws.onopen = () => setLoading(false);
+        ws.onopen = () => {
+            ws.send(JSON.stringify({ type: 'hello', data: { version: PROTOCOL_VERSION } }));
+            setLoading(false);
+        };
         ws.onclose = () => {
             setLoading(true);
             setWs(undefined);
@@ -97,13 +168,13 @@ const Chat: Component<{ toggleSidebar?: () => void }> = (props) => {
         }
     });
 
-    function submitChat(e: SubmitEvent, user: IUser) {
+    function submitChat(e: SubmitEvent) {
         e.preventDefault();
         const target = input();
         if (!target) return;
         const content = target.value.trim();
         if (!content.length) return;
-        ws()?.send(JSON.stringify({ author: user.username, content: target.value, reply_to: replying() || undefined }));
+        ws()?.send(JSON.stringify({ type: 'send', data: { content: target.value, reply_to: replying() || undefined } }));
         target.value = '';
         setReplying(false);
     }
@@ -145,20 +216,25 @@ const Chat: Component<{ toggleSidebar?: () => void }> = (props) => {
             <div class="flex justify-between p-2 text-lg border-b border-b-neutral-700">
                 <button onclick={toggleSidebar} class="btn btn-square btn-outline btn-sm">{hideIcon}</button>
                 <span>Stream Chat</span>
-                <span>Room</span>
+                <span title={presence() ? `${presence().authenticated} logged in, ${presence().anonymous} anonymous` : undefined}>
+                    {presence() ? `${presence().total} in chat` : 'Room'}
+                </span>
             </div>
             <Show when={!loading()} fallback={<div class="text-3xl flex-grow grid place-items-center">Loading...</div>}>
                 <div class="flex flex-col-reverse overflow-y-auto flex-grow pb-2">
                     <For each={chatState}>
                         {(cm, i) => (
-                            <ChatMessage position={calculatePos(i())} message={cm as IncomingMessage} repliedmsg={chatState.find(m => m.message_id === cm.reply_to)} reply={setReplying} />
+                            <ChatMessage position={calculatePos(i())} message={cm as IncomingMessage} repliedmsg={chatState.find(m => m.message_id === cm.reply_to) ?? cm.reply} reply={setReplying} />
                         )}
                     </For>
                 </div>
             </Show>
             <Show when={authService().user}>
-                {user => (
-                    <form onsubmit={e => submitChat(e, user())} class="flex flex-col gap-1">
+                {() => (
+                    <form onsubmit={submitChat} class="flex flex-col gap-1">
+                        <Show when={chatError()}>
+                            <div class="text-sm text-warning px-2">{chatError()}</div>
+                        </Show>
                         <div class="join join-vertical">
                             <Show when={replyto()}>
                                 {msg => (
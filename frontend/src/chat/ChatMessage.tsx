@@ -20,14 +20,27 @@ export type IncomingMessage = {
     author: string;
     timestamp: string;
     reply_to?: string;
+    reply?: RepliedMessage;
+    emotes?: EmotePosition[];
 };
 
+// Emotes found by the server, offsets are in unicode code points
+export type EmotePosition = {
+    id: string;
+    name: string;
+    url: string;
+    start: number;
+    end: number;
+};
+
+export type RepliedMessage = Pick<IncomingMessage, 'message_id' | 'author' | 'content'>;
+
 export type MessagePosition = 'start' | 'middle' | 'end' | 'single';
 
 type Props = {
     message: IncomingMessage,
     position: MessagePosition,
-    repliedmsg?: IncomingMessage,
+    repliedmsg?: RepliedMessage,
     reply: (_: string) => void,
 };
 
@@ -72,6 +85,10 @@ const ChatMessage: Component<Props> = (props) => {
     const { emoteSet, globalEmoteSet } = useRouteData<typeof StreamData>();
 
     const message = createMemo(() => {
+        if (props.message.emotes) {
+            const emotes = props.message.emotes.map(e => ({ name: e.name, data: { host: { url: e.url } } }));
+            return parseEmotes(props.message.content, emotes, authService().user);
+        }
         let emotes = globalEmoteSet()?.emotes ?? [];
         emotes = emotes.concat(emoteSet()?.emotes ?? []);
         return parseEmotes(props.message.content, emotes, authService().user);
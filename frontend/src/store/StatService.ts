@@ -7,6 +7,9 @@ export function StatService() {
     return {
         getViewers(user: string): Promise<number> {
             return client.stats.viewerCount(user);
+        },
+        streamEvents(user: string): EventSource {
+            return new EventSource(`${endpoint}/stream/${user}/events`);
         }
     }
 }
@@ -1,4 +1,4 @@
-import { IStreamOption, IUser } from "../types/user.interface";
+import { IFollow, ILiveStream, INotification, INotificationSink, IRecording, IStreamOption, IUser, OfflineChat, SinkKind } from "../types/user.interface";
 
 function httpClient(endpoint: string, request: typeof fetch) {
   // let auth = "";
@@ -67,8 +67,8 @@ export function ovenAuthClient(endpoint: string, request = fetch) {
   return {
     stats: {
       async viewerCount(user: string): Promise<number> {
-        return client.get('/viewers/' + user)('response').then(response => {
-          return response.totalConnections
+        return client.get(`/stream/${user}/stats`)('stats').then(stats => {
+          return stats.viewers
         }).catch(_ => -1);
       }
     },
@@ -76,6 +76,9 @@ export function ovenAuthClient(endpoint: string, request = fetch) {
       users(): Promise<IUser[]> {
         return client.get('/user/users')('users');
       },
+      streams(): Promise<ILiveStream[]> {
+        return client.get('/stream')('streams');
+      },
       options(): Promise<IStreamOption> {
         return client.get('/user/options')('options');
       },
@@ -90,6 +93,60 @@ export function ovenAuthClient(endpoint: string, request = fetch) {
       },
       set_name(name: string): Promise<IStreamOption> {
         return client.put('/user/options', { name })();
+      },
+      set_offline_chat(offline_chat: OfflineChat): Promise<IStreamOption> {
+        return client.put('/user/options', { offline_chat })();
+      },
+      stop_stream(): Promise<{ stopped: boolean, options: IStreamOption }> {
+        return client.post('/user/stream/stop')();
+      },
+      set_record(record: boolean): Promise<IStreamOption> {
+        return client.put('/user/options', { record })();
+      },
+      start_recording(): Promise<IRecording> {
+        return client.post('/user/recording/start')('recording');
+      },
+      stop_recording(): Promise<IRecording[]> {
+        return client.post('/user/recording/stop')('recordings');
+      },
+      recordings(): Promise<IRecording[]> {
+        return client.get('/user/recordings')('recordings');
+      },
+      sinks(): Promise<INotificationSink[]> {
+        return client.get('/user/notifications/sinks')('sinks');
+      },
+      add_sink(kind: SinkKind, url: string): Promise<INotificationSink> {
+        return client.post('/user/notifications/sinks', { kind, url })('sink');
+      },
+      delete_sink(id: number): Promise<number> {
+        return client.delete(`/user/notifications/sinks/${id}`)('deleted');
+      },
+      follows(): Promise<IFollow[]> {
+        return client.get('/user/follows')('follows');
+      },
+      follow(user: string): Promise<boolean> {
+        return client.put(`/user/follows/${user}`)('following');
+      },
+      unfollow(user: string): Promise<boolean> {
+        return client.delete(`/user/follows/${user}`)('following');
+      },
+      followed_streams(): Promise<ILiveStream[]> {
+        return client.get('/user/following/live')('streams');
+      },
+      inbox(): Promise<{ notifications: INotification[], unread: number }> {
+        return client.get('/user/notifications/inbox')();
+      },
+      read_inbox(): Promise<number> {
+        return client.post('/user/notifications/inbox/read')('unread');
+      },
+      push_key(): Promise<string> {
+        return client.get('/user/push/key')('key');
+      },
+      push_subscribe(subscription: PushSubscriptionJSON): Promise<boolean> {
+        return client.post('/user/push/subscriptions', subscription as Record<string, unknown>)('subscribed');
+      },
+      push_unsubscribe(endpoint: string): Promise<boolean> {
+        return client.delete('/user/push/subscriptions', { endpoint })('subscribed');
       }
     },
 
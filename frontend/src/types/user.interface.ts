@@ -2,12 +2,63 @@ export type IUser = {
     id: number;
     username: string;
     hidden: boolean;
+    /** Only set on the logged in user */
+    admin?: boolean;
 };
 
 export type IStreamOption = {
     token: string;
     name?: string;
     emote_id?: string;
-    public: boolean
+    public: boolean;
+    offline_chat: OfflineChat;
+    record: boolean;
 };
 
+export type ILiveStream = {
+    username: string;
+    name?: string;
+    protocol: string;
+    started_at: string;
+    viewers: number;
+};
+
+export type IRecording = {
+    id: number;
+    record_id: string;
+    state: 'ready' | 'recording' | 'stopping' | 'stopped' | 'error';
+    file_path?: string;
+    info_path?: string;
+    bytes?: number;
+    // milliseconds
+    duration?: number;
+    started_at?: string;
+    finished_at?: string;
+    created_at: string;
+};
+
+export type SinkKind = 'webhook' | 'discord';
+
+export type INotificationSink = {
+    id: number;
+    kind: SinkKind;
+    url: string;
+    created_at: string;
+};
+
+export type IFollow = {
+    username: string;
+    created_at: string;
+};
+
+export type INotification = {
+    id: number;
+    event: 'live';
+    payload: { username: string, title?: string, url?: string, started_at: string };
+    read_at?: string;
+    created_at: string;
+};
+
+// Who may chat while the stream is offline
+export type OfflineChat = 'open' | 'followers' | 'readonly';
+
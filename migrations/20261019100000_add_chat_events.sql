@@ -0,0 +1,5 @@
+create table chat_events (
+    id bigint generated always as identity primary key,
+    payload jsonb not null,
+    created_at timestamptz not null default now()
+);
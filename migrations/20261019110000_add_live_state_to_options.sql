@@ -0,0 +1,5 @@
+alter table options
+add column live_since timestamptz,
+add column live_until timestamptz,
+add column offline_chat text not null default 'open'
+    check (offline_chat in ('open', 'followers', 'readonly'));
@@ -0,0 +1,12 @@
+create table stream_sessions (
+    id bigint generated always as identity primary key,
+    user_id integer not null references users(id) on update cascade on delete cascade,
+    protocol text not null,
+    address text not null,
+    port integer not null,
+    user_agent text,
+    started_at timestamptz not null,
+    ended_at timestamptz
+);
+
+create index stream_sessions_live on stream_sessions (user_id) where ended_at is null;
@@ -0,0 +1 @@
+alter table users add column admin boolean not null default false;
@@ -0,0 +1,20 @@
+alter table options add column record boolean not null default false;
+
+create table recordings (
+    id bigint generated always as identity primary key,
+    user_id integer not null references users(id) on update cascade on delete cascade,
+    -- Id of the recording in OvenMediaEngine
+    record_id text not null unique,
+    state text not null
+        check (state in ('ready', 'recording', 'stopping', 'stopped', 'error')),
+    file_path text,
+    info_path text,
+    bytes bigint,
+    -- Milliseconds
+    duration bigint,
+    started_at timestamptz,
+    finished_at timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index recordings_user on recordings (user_id, created_at desc);
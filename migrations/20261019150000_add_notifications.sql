@@ -0,0 +1,25 @@
+create table notification_sinks (
+    id bigint generated always as identity primary key,
+    user_id integer not null references users(id) on update cascade on delete cascade,
+    kind text not null check (kind in ('webhook', 'discord')),
+    url text not null,
+    created_at timestamptz not null default now()
+);
+
+create index notification_sinks_user on notification_sinks (user_id);
+
+create table notification_deliveries (
+    id bigint generated always as identity primary key,
+    sink_id bigint not null references notification_sinks(id) on delete cascade,
+    event text not null,
+    payload jsonb not null,
+    status text not null default 'pending'
+        check (status in ('pending', 'delivered', 'failed')),
+    attempts integer not null default 0,
+    response_status integer,
+    error text,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create index notification_deliveries_sink on notification_deliveries (sink_id, created_at desc);
@@ -0,0 +1,20 @@
+create table follows (
+    follower_id integer not null references users(id) on update cascade on delete cascade,
+    streamer_id integer not null references users(id) on update cascade on delete cascade,
+    created_at timestamptz not null default now(),
+    primary key (follower_id, streamer_id),
+    check (follower_id <> streamer_id)
+);
+
+create index follows_streamer on follows (streamer_id);
+
+create table notifications (
+    id bigint generated always as identity primary key,
+    user_id integer not null references users(id) on update cascade on delete cascade,
+    event text not null,
+    payload jsonb not null,
+    read_at timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index notifications_user on notifications (user_id, id desc);
@@ -0,0 +1,17 @@
+-- There is only ever one key, browsers bind subscriptions to it
+create table vapid_keys (
+    id boolean primary key default true check (id),
+    pkcs8 bytea not null,
+    created_at timestamptz not null default now()
+);
+
+create table push_subscriptions (
+    id bigint generated always as identity primary key,
+    user_id integer not null references users(id) on update cascade on delete cascade,
+    endpoint text not null unique,
+    p256dh bytea not null,
+    auth bytea not null,
+    created_at timestamptz not null default now()
+);
+
+create index push_subscriptions_user on push_subscriptions (user_id);
@@ -0,0 +1,32 @@
+create table event_hooks (
+    id bigint generated always as identity primary key,
+    -- Global hooks without a user get everyone's events
+    user_id integer references users(id) on update cascade on delete cascade,
+    url text not null,
+    secret text not null,
+    -- Empty for all events
+    events text[] not null default '{}',
+    created_at timestamptz not null default now()
+);
+
+create index event_hooks_user on event_hooks (user_id);
+
+create table event_deliveries (
+    id bigint generated always as identity primary key,
+    hook_id bigint not null references event_hooks(id) on delete cascade,
+    -- Same for every hook the event went to, so receivers can deduplicate
+    event_id text not null,
+    event text not null,
+    payload jsonb not null,
+    status text not null default 'pending'
+        check (status in ('pending', 'delivered', 'failed')),
+    attempts integer not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    response_status integer,
+    error text,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create index event_deliveries_due on event_deliveries (next_attempt_at) where status = 'pending';
+create index event_deliveries_hook on event_deliveries (hook_id, id desc);
@@ -0,0 +1,11 @@
+-- Stream status changes for the SSE feed, kept around so clients can resume
+create table stream_events (
+    id bigint generated always as identity primary key,
+    stream text not null,
+    -- Whether the global feed shows it
+    public boolean not null,
+    event jsonb not null,
+    created_at timestamptz not null default now()
+);
+
+create index stream_events_stream on stream_events (stream, id);
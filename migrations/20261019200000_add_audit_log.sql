@@ -0,0 +1,25 @@
+create table audit_log (
+    id bigint generated always as identity primary key,
+    -- Whose account the entry is about, kept when the user is deleted
+    user_id integer,
+    -- Who did it, the attempted username for failed logins
+    actor text,
+    event text not null,
+    ip text,
+    user_agent text,
+    details jsonb not null default '{}',
+    created_at timestamptz not null default now()
+);
+
+create index audit_log_user on audit_log (user_id, id desc);
+create index audit_log_actor on audit_log (actor, id desc);
+
+create function audit_log_append_only() returns trigger as $$
+begin
+    raise exception 'audit_log is append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_log_append_only
+    before update or delete on audit_log
+    for each row execute function audit_log_append_only();
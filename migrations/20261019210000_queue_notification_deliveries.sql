@@ -0,0 +1,4 @@
+-- Deliveries are queued and leased like event deliveries, so they survive restarts
+alter table notification_deliveries add column next_attempt_at timestamptz not null default now();
+
+create index notification_deliveries_due on notification_deliveries (next_attempt_at) where status = 'pending';
@@ -0,0 +1,4 @@
+-- Whether a user is live follows from their open stream sessions
+alter table options
+drop column live_since,
+drop column live_until;
@@ -0,0 +1,66 @@
+use axum::{
+    extract::{Path, State},
+    http::{Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Json, Router,
+};
+use axum_login::RequireAuthorizationLayer;
+use serde_json::json;
+use sqlx::PgPool;
+
+use crate::{
+    audit::{self, AuditEvent, Source},
+    error::OvenauthError,
+    hooks::{self, Scope},
+    ome::Ome,
+    stream,
+    user::User,
+};
+
+async fn require_admin<B>(
+    user: Option<Extension<User>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match user {
+        Some(Extension(user)) if user.admin => next.run(req).await,
+        _ => StatusCode::FORBIDDEN.into_response(),
+    }
+}
+
+async fn stop_stream(
+    Path(username): Path<String>,
+    Extension(admin): Extension<User>,
+    Extension(ome): Extension<Ome>,
+    source: Source,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let user = User::from_username(&username, &db).await?;
+    let (stopped, _) = stream::stop(&user, &db, &ome).await?;
+    audit::record(
+        Some(user.id),
+        Some(&admin.username),
+        AuditEvent::KeyRotated,
+        &source,
+        json!({ "via": "admin_stop", "stopped": stopped }),
+        &db,
+    )
+    .await;
+    tracing::warn!(
+        admin = admin.username,
+        user = user.username,
+        "Stream stopped by admin"
+    );
+    Ok(Json(json!({ "stopped": stopped })))
+}
+
+pub fn routes() -> Router<PgPool> {
+    Router::new()
+        .route("/stream/:stream/stop", post(stop_stream))
+        .nest("/hooks", hooks::routes(Scope::Global))
+        .nest("/audit", audit::admin_routes())
+        .route_layer(middleware::from_fn(require_admin))
+        .route_layer(RequireAuthorizationLayer::<i32, User>::login())
+}
@@ -0,0 +1,185 @@
+use std::{convert::Infallible, env, net::SocketAddr};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Query, State},
+    http::{header::USER_AGENT, request::Parts},
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
+use axum_login::RequireAuthorizationLayer;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use crate::{error::OvenauthError, user::User};
+
+const MAX_ENTRIES: i64 = 500;
+
+/// Something security relevant that happened to an account.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Login,
+    LoginFailed,
+    Registered,
+    KeyRotated,
+    WebhookDenied,
+}
+
+/// Where a request came from. The first `X-Forwarded-For` address is only
+/// trusted with `TRUST_FORWARDED_FOR`, when running behind a proxy.
+#[derive(Debug, Clone, Default)]
+pub struct Source {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Source {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let forwarded = env::var("TRUST_FORWARDED_FOR")
+            .is_ok()
+            .then(|| parts.headers.get("x-forwarded-for"))
+            .flatten()
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.split(',').next())
+            .map(|ip| ip.trim().to_string());
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string);
+        Ok(Self {
+            ip: forwarded.or(peer),
+            user_agent,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub id: i64,
+    pub user_id: Option<i32>,
+    pub actor: Option<String>,
+    pub event: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuditQuery {
+    /// Actor, including failed logins of unknown users
+    actor: Option<String>,
+    event: Option<AuditEvent>,
+    ip: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// Entries older than this id, for paging
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Appends to the log. Failing to do so is logged, but doesn't fail what
+/// was audited.
+pub async fn record(
+    user_id: Option<i32>,
+    actor: Option<&str>,
+    event: AuditEvent,
+    source: &Source,
+    details: Value,
+    pool: &PgPool,
+) {
+    let res = sqlx::query!(
+        r#"--sql
+        insert into audit_log (user_id, actor, event, ip, user_agent, details)
+        values ($1, $2, $3, $4, $5, $6)
+        "#,
+        user_id,
+        actor,
+        event as _,
+        source.ip,
+        source.user_agent,
+        details
+    )
+    .execute(pool)
+    .await;
+    if let Err(e) = res {
+        tracing::error!(%e, ?event, actor, "Failed to write audit log");
+    }
+}
+
+impl Entry {
+    async fn find(
+        user_id: Option<i32>,
+        query: &AuditQuery,
+        pool: &PgPool,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select id, user_id, actor, event, ip, user_agent, details, created_at
+            from audit_log
+            where ($1::integer is null or user_id = $1)
+                and ($2::text is null or actor = $2)
+                and ($3::text is null or event = $3)
+                and ($4::text is null or ip = $4)
+                and ($5::timestamptz is null or created_at >= $5)
+                and ($6::timestamptz is null or created_at < $6)
+                and ($7::bigint is null or id < $7)
+            order by id desc
+            limit $8
+            "#,
+            user_id,
+            query.actor,
+            query.event as _,
+            query.ip,
+            query.since,
+            query.until,
+            query.before,
+            query.limit.unwrap_or(100).clamp(1, MAX_ENTRIES)
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
+
+// ROUTES
+async fn own(
+    Extension(user): Extension<User>,
+    Query(query): Query<AuditQuery>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let entries = Entry::find(Some(user.id), &query, &db).await?;
+    Ok(Json(json!({ "entries": entries })))
+}
+
+async fn all(
+    Query(query): Query<AuditQuery>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let entries = Entry::find(None, &query, &db).await?;
+    Ok(Json(json!({ "entries": entries })))
+}
+
+pub fn routes() -> Router<PgPool> {
+    Router::new()
+        .route("/", get(own))
+        .route_layer(RequireAuthorizationLayer::<i32, User>::login())
+}
+
+/// Everyone's log, has to be nested behind the admin check.
+pub fn admin_routes() -> Router<PgPool> {
+    Router::new().route("/", get(all))
+}
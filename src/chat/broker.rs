@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use ulid::Ulid;
+
+use super::protocol::MessageType;
+
+const CHANNEL: &str = "ovenauth_chat";
+// NOTIFY payloads must be shorter than 8000 bytes
+const MAX_PAYLOAD: usize = 7900;
+// Keep in sync with the cleanup query
+const STORED_EVENT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Something one chat node tells all the others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub node: Ulid,
+    pub room: String,
+    pub event: BrokerEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+pub enum BrokerEvent {
+    /// An event for the room's clients
+    Event(MessageType),
+    /// Everyone connected to the room on the sending node
+    Presence {
+        users: HashSet<String>,
+        anonymous: usize,
+    },
+}
+
+/// Fans chat events out to every ovenauth instance.
+#[async_trait]
+pub trait Broker: fmt::Debug + Send + Sync {
+    async fn publish(&self, envelope: &Envelope) -> Result<()>;
+    /// Envelopes published by any node, including this one.
+    async fn subscribe(&self) -> Result<mpsc::Receiver<Envelope>>;
+}
+
+/// For single instance deployments, where there is no one to talk to.
+#[derive(Debug)]
+pub struct LocalBroker;
+
+#[async_trait]
+impl Broker for LocalBroker {
+    async fn publish(&self, _envelope: &Envelope) -> Result<()> {
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<mpsc::Receiver<Envelope>> {
+        Ok(mpsc::channel(1).1)
+    }
+}
+
+/// Envelopes too large for a NOTIFY payload are stored in `chat_events` and
+/// only their id is sent.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Inline(Box<Envelope>),
+    Stored { event_id: i64 },
+}
+
+/// Uses Postgres LISTEN/NOTIFY on the database every node shares anyway.
+#[derive(Debug)]
+pub struct PgBroker {
+    pool: PgPool,
+}
+
+impl PgBroker {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    async fn resolve(&self, payload: &str) -> Result<Envelope> {
+        Ok(match serde_json::from_str(payload)? {
+            Payload::Inline(envelope) => *envelope,
+            Payload::Stored { event_id } => {
+                let payload =
+                    sqlx::query_scalar!("select payload from chat_events where id = $1", event_id)
+                        .fetch_one(&self.pool)
+                        .await?;
+                serde_json::from_value(payload)?
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl Broker for PgBroker {
+    async fn publish(&self, envelope: &Envelope) -> Result<()> {
+        let mut payload = serde_json::to_string(&Payload::Inline(Box::new(envelope.clone())))?;
+        if payload.len() > MAX_PAYLOAD {
+            let event_id = sqlx::query_scalar!(
+                "insert into chat_events (payload) values ($1) returning id",
+                serde_json::to_value(envelope)?
+            )
+            .fetch_one(&self.pool)
+            .await?;
+            payload = serde_json::to_string(&Payload::Stored { event_id })?;
+        }
+        // query! can't describe the void pg_notify returns
+        sqlx::query("select pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<mpsc::Receiver<Envelope>> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANNEL).await?;
+        let (tx, rx) = mpsc::channel(100);
+        let broker = Self::new(self.pool.clone());
+        tokio::task::Builder::new()
+            .name("chat_broker")
+            .spawn(async move {
+                let mut cleanup = tokio::time::interval(STORED_EVENT_TTL);
+                loop {
+                    tokio::select! {
+                        _ = cleanup.tick() => {
+                            if let Err(e) = sqlx::query!(
+                                "delete from chat_events where created_at < now() - interval '5 minutes'"
+                            )
+                            .execute(&broker.pool)
+                            .await
+                            {
+                                tracing::error!(%e, "Failed to clean up chat events");
+                            }
+                        },
+                        // recv reconnects on its own, notifications sent in between are lost
+                        notification = listener.recv() => {
+                            let envelope = match notification {
+                                Ok(n) => broker.resolve(n.payload()).await,
+                                Err(e) => Err(e.into()),
+                            };
+                            match envelope {
+                                Ok(envelope) => {
+                                    if tx.send(envelope).await.is_err() {
+                                        return;
+                                    }
+                                }
+                                Err(e) => tracing::error!(%e, "Failed to receive chat event"),
+                            }
+                        },
+                    }
+                }
+            })
+            .expect("Task to be created");
+        Ok(rx)
+    }
+}
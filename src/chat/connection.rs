@@ -0,0 +1,452 @@
+use std::ops::ControlFlow;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use axum::extract::ws::{close_code, Message};
+use chrono::Utc;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::Instrument;
+use ulid::Ulid;
+
+use super::protocol::{
+    close_frame, ClientMessage, ErrorCode, IncomingMessage, MessageType, ModCommand,
+    OutgoingMessage, ReplySnippet, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use super::room::RoomState;
+use super::{ChatContext, BUFFERSIZE};
+use crate::emotes::EmotePosition;
+use crate::follow::Follow;
+use crate::hooks::{self, Event};
+use crate::options::{ChatOptions, OfflineChat};
+use crate::user::User;
+
+const MAX_MESSAGE_LENGTH: usize = 500;
+// Allow bursts of RATE_LIMIT_BURST messages, refilling one every RATE_LIMIT_REFILL
+const RATE_LIMIT_BURST: u32 = 5;
+const RATE_LIMIT_REFILL: Duration = Duration::from_secs(1);
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+const MAX_MUTE_SECS: u64 = 60 * 60 * 24 * 365;
+
+#[derive(Debug)]
+struct RateLimiter {
+    tokens: u32,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            tokens: RATE_LIMIT_BURST,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self) -> bool {
+        let refills = self.last_refill.elapsed().as_millis() / RATE_LIMIT_REFILL.as_millis();
+        if refills > 0 {
+            let refills = refills.min(RATE_LIMIT_BURST.into()) as u32;
+            self.tokens = (self.tokens + refills).min(RATE_LIMIT_BURST);
+            self.last_refill = Instant::now();
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+/// Per socket state of the receiving side of a chat connection.
+#[derive(Debug)]
+pub struct Connection {
+    ctx: ChatContext,
+    room: RoomState,
+    user: Option<User>,
+    version: u32,
+    direct_tx: mpsc::Sender<Message>,
+    limiter: RateLimiter,
+    last_typing: Option<Instant>,
+}
+
+impl Connection {
+    pub fn new(
+        ctx: ChatContext,
+        room: RoomState,
+        user: Option<User>,
+        direct_tx: mpsc::Sender<Message>,
+    ) -> Self {
+        Self {
+            ctx,
+            room,
+            user,
+            version: 0,
+            direct_tx,
+            limiter: RateLimiter::new(),
+            last_typing: None,
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Sends a frame to this connection only.
+    pub async fn send_direct(&self, msg: Message) {
+        // Only fails when send_task is gone, and then the connection is closing anyway
+        let _ = self.direct_tx.send(msg).await;
+    }
+
+    pub async fn error(&self, code: ErrorCode, message: impl Into<String>, r#ref: Option<Ulid>) {
+        self.send_direct(MessageType::error(code, message, r#ref).to_frame())
+            .await;
+    }
+
+    pub async fn handle(&mut self, msg: ClientMessage) -> ControlFlow<()> {
+        let username = self.user.as_ref().map(|u| u.username.clone());
+        match (msg, username) {
+            (ClientMessage::Hello { version }, _) => return self.hello(version).await,
+            (ClientMessage::History { before, limit }, _) => self.history(before, limit).await,
+            (_, None) => {
+                self.error(ErrorCode::Unauthenticated, "Login to chat", None)
+                    .await
+            }
+            (ClientMessage::Send(msg), Some(author)) => self.send(author, msg).await,
+            (ClientMessage::Delete(message_id), Some(username)) => {
+                self.delete(&username, message_id).await
+            }
+            (
+                ClientMessage::Edit {
+                    message_id,
+                    content,
+                },
+                Some(username),
+            ) => self.edit(&username, message_id, content).await,
+            (ClientMessage::Typing, Some(username)) => self.typing(username).await,
+            (ClientMessage::Revisions(message_id), Some(username)) => {
+                self.revisions(&username, message_id).await
+            }
+            (ClientMessage::Mod(cmd), Some(username)) => self.moderate(&username, cmd).await,
+        }
+        ControlFlow::Continue(())
+    }
+
+    async fn hello(&mut self, version: u32) -> ControlFlow<()> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            self.send_direct(close_frame(
+                close_code::PROTOCOL,
+                "Unsupported protocol version",
+            ))
+            .await;
+            return ControlFlow::Break(());
+        }
+        self.version = version;
+        ControlFlow::Continue(())
+    }
+
+    async fn is_muted(&self, username: &str) -> bool {
+        match self.room.muted.read().await.get(username) {
+            Some(Some(until)) => *until > Utc::now(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Why `username` may not chat while the stream is offline, if they may not.
+    async fn offline_restriction(&self, username: &str) -> Option<&'static str> {
+        if username == self.room.name || self.room.live.load(Ordering::Relaxed) {
+            return None;
+        }
+        // Our flag might have missed the stream going live, the database knows for sure
+        let options = match ChatOptions::from_username(&self.room.name, &self.ctx.pool).await {
+            Ok(Some(options)) if !options.live => options,
+            Ok(_) => return None,
+            Err(e) => {
+                tracing::error!(%e, room = self.room.name, "Failed to look up chat options");
+                return None;
+            }
+        };
+        match options.offline_chat {
+            OfflineChat::Open => None,
+            OfflineChat::Followers => {
+                match Follow::exists(username, &self.room.name, &self.ctx.pool).await {
+                    Ok(true) => None,
+                    Ok(false) => Some("Only followers can chat while the stream is offline"),
+                    Err(e) => {
+                        tracing::error!(%e, room = self.room.name, "Failed to look up follow");
+                        None
+                    }
+                }
+            }
+            OfflineChat::ReadOnly => Some("Chat is read-only while the stream is offline"),
+        }
+    }
+
+    /// Checks shared by everything that puts user content into the room.
+    async fn check_content(&mut self, username: &str, content: &str) -> bool {
+        if self.is_muted(username).await {
+            self.error(ErrorCode::Muted, "You are muted in this room", None)
+                .await;
+            return false;
+        }
+        if let Some(reason) = self.offline_restriction(username).await {
+            self.error(ErrorCode::Forbidden, reason, None).await;
+            return false;
+        }
+        if content.trim().is_empty() || content.chars().count() > MAX_MESSAGE_LENGTH {
+            self.error(
+                ErrorCode::InvalidMessage,
+                format!("Message must be between 1 and {MAX_MESSAGE_LENGTH} characters"),
+                None,
+            )
+            .await;
+            return false;
+        }
+        if !self.limiter.try_acquire() {
+            self.error(
+                ErrorCode::RateLimited,
+                "You are sending messages too fast",
+                None,
+            )
+            .await;
+            return false;
+        }
+        true
+    }
+
+    async fn emotes(&self, content: &str) -> Vec<EmotePosition> {
+        self.ctx
+            .emotes
+            .for_room(&self.room.name, &self.ctx.pool)
+            .await
+            .tokenize(content)
+    }
+
+    async fn send(&mut self, author: String, msg: IncomingMessage) {
+        if !self.check_content(&author, &msg.content).await {
+            return;
+        }
+        let emotes = self.emotes(&msg.content).await;
+        let mut outgoing = OutgoingMessage {
+            message_id: Ulid::new(),
+            content: msg.content,
+            emotes,
+            author,
+            timestamp: Utc::now(),
+            reply_to: msg.reply_to,
+            reply: None,
+            edited_at: None,
+            revisions: Vec::new(),
+        };
+        if let Some(reply_to) = msg.reply_to {
+            let reply = self
+                .room
+                .messagebuffer
+                .read()
+                .await
+                .iter()
+                .find(|m| m.message_id == reply_to)
+                .map(ReplySnippet::new);
+            if reply.is_none() {
+                self.error(
+                    ErrorCode::UnknownMessage,
+                    "The message you replied to does not exist",
+                    Some(reply_to),
+                )
+                .await;
+                return;
+            }
+            outgoing.reply = reply;
+        }
+        self.ctx
+            .publish(&self.room, MessageType::Msg(outgoing.clone()))
+            .await;
+        metrics::counter!("ovenauth_chat_messages_total").increment(1);
+        // Queuing it for hooks takes a few queries, the room doesn't wait for them
+        let pool = self.ctx.pool.clone();
+        let room = self.room.name.clone();
+        let event = Event::ChatMessage {
+            room: room.clone(),
+            message: Box::new(outgoing),
+        };
+        self.ctx
+            .health
+            .spawn(async move { hooks::emit(&pool, &room, event).await }.in_current_span());
+    }
+
+    async fn delete(&self, username: &str, message_id: Ulid) {
+        let res = match self
+            .room
+            .messagebuffer
+            .read()
+            .await
+            .iter()
+            .find(|m| m.message_id == message_id)
+        {
+            None => Err((ErrorCode::UnknownMessage, "Message not found")),
+            Some(m) if m.author != username && username != self.room.name => Err((
+                ErrorCode::Forbidden,
+                "You can only delete your own messages",
+            )),
+            Some(_) => Ok(()),
+        };
+        match res {
+            Ok(()) => {
+                self.ctx
+                    .publish(&self.room, MessageType::Delete(message_id))
+                    .await
+            }
+            Err((code, message)) => self.error(code, message, Some(message_id)).await,
+        }
+    }
+
+    async fn edit(&mut self, username: &str, message_id: Ulid, content: String) {
+        if !self.check_content(username, &content).await {
+            return;
+        }
+        let emotes = self.emotes(&content).await;
+        let now = Utc::now();
+        let res = match self
+            .room
+            .messagebuffer
+            .read()
+            .await
+            .iter()
+            .find(|m| m.message_id == message_id)
+        {
+            None => Err((ErrorCode::UnknownMessage, "Message not found")),
+            Some(m) if m.author != username => {
+                Err((ErrorCode::Forbidden, "You can only edit your own messages"))
+            }
+            Some(m)
+                if (now - m.timestamp).to_std().unwrap_or_default()
+                    > self.ctx.config.edit_window =>
+            {
+                Err((ErrorCode::Forbidden, "This message can no longer be edited"))
+            }
+            Some(_) => Ok(()),
+        };
+        match res {
+            Ok(()) => {
+                let edit = MessageType::Edit {
+                    message_id,
+                    content,
+                    emotes,
+                    edited_at: now,
+                };
+                self.ctx.publish(&self.room, edit).await;
+            }
+            Err((code, message)) => self.error(code, message, Some(message_id)).await,
+        }
+    }
+
+    async fn typing(&mut self, username: String) {
+        if self
+            .last_typing
+            .is_some_and(|t| t.elapsed() < TYPING_INTERVAL)
+            || self.is_muted(&username).await
+        {
+            return;
+        }
+        self.last_typing = Some(Instant::now());
+        self.ctx
+            .publish(&self.room, MessageType::Typing(username))
+            .await;
+    }
+
+    async fn history(&self, before: Option<Ulid>, limit: Option<usize>) {
+        let limit = limit.unwrap_or(BUFFERSIZE).min(BUFFERSIZE);
+        let messages = {
+            let msgbuff = self.room.messagebuffer.read().await;
+            let end = match before {
+                Some(before) => msgbuff.iter().position(|m| m.message_id == before),
+                None => Some(msgbuff.len()),
+            };
+            end.map(|end| {
+                msgbuff
+                    .range(end.saturating_sub(limit)..end)
+                    .cloned()
+                    .collect()
+            })
+        };
+        match messages {
+            Some(messages) => {
+                self.send_direct(MessageType::History(messages).to_frame())
+                    .await
+            }
+            None => {
+                self.error(ErrorCode::UnknownMessage, "Message not found", before)
+                    .await
+            }
+        }
+    }
+
+    async fn revisions(&self, username: &str, message_id: Ulid) {
+        if username != self.room.name {
+            self.error(
+                ErrorCode::Forbidden,
+                "Only the room owner can see edit history",
+                Some(message_id),
+            )
+            .await;
+            return;
+        }
+        let revisions = self
+            .room
+            .messagebuffer
+            .read()
+            .await
+            .iter()
+            .find(|m| m.message_id == message_id)
+            .map(|m| m.revisions.clone());
+        match revisions {
+            Some(revisions) => {
+                self.send_direct(
+                    MessageType::Revisions {
+                        message_id,
+                        revisions,
+                    }
+                    .to_frame(),
+                )
+                .await
+            }
+            None => {
+                self.error(
+                    ErrorCode::UnknownMessage,
+                    "Message not found",
+                    Some(message_id),
+                )
+                .await
+            }
+        }
+    }
+
+    async fn moderate(&self, username: &str, cmd: ModCommand) {
+        if username != self.room.name {
+            self.error(
+                ErrorCode::Forbidden,
+                "Only the room owner can moderate",
+                None,
+            )
+            .await;
+            return;
+        }
+        match cmd {
+            ModCommand::Mute { username, duration } => {
+                let until = duration
+                    .map(|d| Utc::now() + chrono::Duration::seconds(d.min(MAX_MUTE_SECS) as i64));
+                self.ctx
+                    .publish(&self.room, MessageType::Mute { username, until })
+                    .await;
+            }
+            ModCommand::Unmute { username } => {
+                if self.room.muted.read().await.contains_key(&username) {
+                    self.ctx
+                        .publish(&self.room, MessageType::Unmute(username))
+                        .await;
+                }
+            }
+            ModCommand::Clear => self.ctx.publish(&self.room, MessageType::Clear).await,
+        }
+    }
+}
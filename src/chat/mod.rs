@@ -0,0 +1,768 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::ws::{close_code, Message, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Router};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use sqlx::PgPool;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span};
+use ulid::Ulid;
+
+use crate::emotes::EmoteCache;
+use crate::error::OvenauthError;
+use crate::health::Health;
+use crate::options::ChatOptions;
+use crate::telemetry;
+use crate::user::User;
+
+mod broker;
+mod connection;
+mod protocol;
+mod room;
+
+pub use protocol::OutgoingMessage;
+
+use broker::{Broker, BrokerEvent, Envelope, LocalBroker, PgBroker};
+use connection::Connection;
+use protocol::{close_frame, ClientMessage, ErrorCode, MessageType};
+use room::{Room, RoomState, Rooms};
+
+const BUFFERSIZE: usize = 50;
+
+/// What a connection talks through, an [`axum::extract::ws::WebSocket`]
+/// outside of tests.
+trait Socket:
+    Sink<Message, Error = axum::Error>
+    + Stream<Item = Result<Message, axum::Error>>
+    + Send
+    + Unpin
+    + 'static
+{
+}
+
+impl<S> Socket for S where
+    S: Sink<Message, Error = axum::Error>
+        + Stream<Item = Result<Message, axum::Error>>
+        + Send
+        + Unpin
+        + 'static
+{
+}
+const DEFAULT_EDIT_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy)]
+struct ChatConfig {
+    /// How long after sending a message its author may still edit it
+    edit_window: Duration,
+}
+
+/// Shared services every connection needs
+#[derive(Debug, Clone)]
+struct ChatContext {
+    config: ChatConfig,
+    emotes: Arc<EmoteCache>,
+    pool: PgPool,
+    broker: Arc<dyn Broker>,
+    /// Identifies this instance to the broker
+    node: Ulid,
+    sockets: Arc<Sockets>,
+    health: Health,
+}
+
+/// Every connection on this node, so a shutdown can reach each one directly
+/// instead of through the rooms, which drop events for slow clients.
+#[derive(Debug, Default)]
+struct Sockets {
+    /// Weak, so a connection's direct channel still closes with it
+    direct: Mutex<HashMap<Ulid, mpsc::WeakSender<Message>>>,
+    /// Drops the sockets that are still open
+    closed: CancellationToken,
+}
+
+impl ChatConfig {
+    fn from_env() -> Self {
+        let edit_window = env::var("CHAT_EDIT_WINDOW")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_EDIT_WINDOW);
+        Self { edit_window }
+    }
+}
+
+impl ChatContext {
+    /// Applies `event` to the room and sends it to its clients on every node.
+    async fn publish(&self, room: &RoomState, event: MessageType) {
+        room.broadcast(event.clone()).await;
+        self.forward(&room.name, BrokerEvent::Event(event)).await;
+    }
+
+    /// Sends `event` to the other nodes only.
+    async fn forward(&self, room: &str, event: BrokerEvent) {
+        let envelope = Envelope {
+            node: self.node,
+            room: room.to_string(),
+            event,
+        };
+        if let Err(e) = self.broker.publish(&envelope).await {
+            tracing::error!(%e, %room, "Failed to publish chat event");
+        }
+    }
+}
+
+/// Applies events from other nodes to our copy of their rooms. Keeps trying
+/// to subscribe, without it this node only sees its own clients.
+async fn listen(state: ChatState, ctx: ChatContext) {
+    let mut backoff = SUBSCRIBE_BACKOFF;
+    let mut rx = loop {
+        match ctx.broker.subscribe().await {
+            Ok(rx) => break rx,
+            Err(e) => {
+                tracing::error!(%e, retry_in = ?backoff, "Failed to subscribe to chat events");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_SUBSCRIBE_BACKOFF);
+            }
+        }
+    };
+    while let Some(envelope) = rx.recv().await {
+        if envelope.node == ctx.node {
+            continue;
+        }
+        let room = state.get(&envelope.room);
+        match envelope.event {
+            BrokerEvent::Event(event) => {
+                let room = {
+                    let mut room = room.lock().expect("Lock not poisoned");
+                    room.remote_event();
+                    room.state.clone()
+                };
+                room.broadcast(event).await;
+            }
+            BrokerEvent::Presence { users, anonymous } => room
+                .lock()
+                .expect("Lock not poisoned")
+                .set_remote(envelope.node, users, anonymous),
+        }
+    }
+}
+
+/// Keeps other nodes' view of our clients fresh, drops nodes and rooms that
+/// went away, and sends clients the viewer counts.
+async fn announce_presence(state: ChatState, ctx: ChatContext) {
+    let mut heartbeat = tokio::time::interval(PRESENCE_INTERVAL);
+    let mut debounce = tokio::time::interval(PRESENCE_DEBOUNCE);
+    loop {
+        let heartbeat = tokio::select! {
+            _ = heartbeat.tick() => true,
+            _ = debounce.tick() => false,
+        };
+        if heartbeat {
+            state.evict_idle();
+            ctx.emotes.evict_expired().await;
+        }
+        let presence: Vec<_> = state
+            .all()
+            .into_iter()
+            .filter_map(|(name, room)| {
+                let mut room = room.lock().expect("Lock not poisoned");
+                if heartbeat {
+                    room.expire_remote(PRESENCE_TIMEOUT);
+                }
+                if let Some(counts) = room.take_counts() {
+                    let _ = room.state.tx.send(MessageType::Presence(counts));
+                }
+                // Logged in users are forwarded right away, anonymous ones only change the counts
+                let forward = if heartbeat {
+                    room.has_local_clients()
+                } else {
+                    room.anonymous_changed()
+                };
+                forward.then(|| (name, room.local_presence()))
+            })
+            .collect();
+        for (room, presence) in presence {
+            ctx.forward(&room, presence).await;
+        }
+    }
+}
+
+type ChatState = Arc<Rooms>;
+
+// Nodes announce their clients this often, and are forgotten after missing a few
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(90);
+// Viewer counts are sent at most this often
+const PRESENCE_DEBOUNCE: Duration = Duration::from_secs(2);
+/// How long rooms nobody is in keep the history other nodes sent
+const REMOTE_HISTORY_RETENTION: Duration = Duration::from_secs(10 * 60);
+// Clients spread their reconnects over this long when a node shuts down
+const RECONNECT_WINDOW: Duration = Duration::from_secs(5);
+/// How long clients get to close their sockets once told to reconnect
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Doubled after every failed attempt to subscribe to other nodes' events
+const SUBSCRIBE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_SUBSCRIBE_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Leaves the room, telling the other nodes if this was a user's last
+/// connection here or nobody is left at all.
+async fn leave(room: &Mutex<Room>, ctx: &ChatContext, username: Option<&str>) {
+    let presence = {
+        let mut room = room.lock().expect("Lock not poisoned");
+        let forward = room.leave(username) || !room.has_local_clients();
+        forward.then(|| (room.state.name.clone(), room.local_presence()))
+    };
+    if let Some((name, presence)) = presence {
+        ctx.forward(&name, presence).await;
+    }
+}
+
+//#[tracing::instrument]
+async fn handle_socket<S: Socket>(
+    socket: S,
+    room: String,
+    live: bool,
+    state: ChatState,
+    ctx: ChatContext,
+    user: Option<User>,
+) {
+    tracing::info!(%room, ?user, "New Websocket connection");
+    let (mut sender, receiver) = socket.split();
+    let name = room;
+    let room = state.enter(&name, live);
+    let username = user.as_ref().map(|u| u.username.as_str());
+    let (room_state, members, counts, presence) = {
+        let mut room = room.lock().expect("Lock not poisoned");
+        // Only announce the first connection of a user on this node
+        let presence = room.join(username).then(|| room.local_presence());
+        (room.state.clone(), room.members(), room.counts(), presence)
+    };
+    if let Some(presence) = presence {
+        ctx.forward(&room_state.name, presence).await;
+    }
+    let (rx, history) = room_state.subscribe().await;
+
+    let greeting = async {
+        let hellomsg = MessageType::Hello {
+            version: protocol::PROTOCOL_VERSION,
+            min_version: protocol::MIN_PROTOCOL_VERSION,
+        };
+        let mut err = sender.send(hellomsg.to_frame()).await.is_err();
+        let userlistmsg = MessageType::Connect(members);
+        err = err || sender.send(userlistmsg.to_frame()).await.is_err();
+        let countsmsg = MessageType::Presence(counts);
+        err = err || sender.send(countsmsg.to_frame()).await.is_err();
+        for m in history {
+            if err {
+                break;
+            }
+            err = sender.send(MessageType::Msg(m).to_frame()).await.is_err();
+        }
+        err
+    };
+    let err = tokio::select! {
+        err = greeting => err,
+        _ = ctx.sockets.closed.cancelled() => true,
+    };
+    if err {
+        leave(&room, &ctx, username).await;
+        state.exit(&name);
+        // return here since this happens before we start any tasks
+        return;
+    }
+
+    // frames addressed only to this connection, like errors and close frames
+    let (direct_tx, direct_rx) = mpsc::channel(16);
+    let id = Ulid::new();
+    ctx.sockets
+        .direct
+        .lock()
+        .expect("Lock not poisoned")
+        .insert(id, direct_tx.downgrade());
+    let mut send_task = tokio::task::Builder::new()
+        .name("send_task")
+        .spawn(send_loop(sender, room_state.clone(), rx, direct_rx).in_current_span())
+        .expect("Task to be created");
+    let mut recv_task = tokio::task::Builder::new()
+        .name("recv_task")
+        .spawn(
+            recv_loop(
+                receiver,
+                Connection::new(ctx.clone(), room_state, user.clone(), direct_tx),
+            )
+            .in_current_span(),
+        )
+        .expect("Task to be created");
+
+    // if anything fails, abort
+    let (task, res) = tokio::select! {
+        res = (&mut send_task) => {
+            // After a close frame recv_task ends with the client's answer,
+            // finishing whatever message it is handling
+            if tokio::time::timeout(Duration::from_secs(5), &mut recv_task).await.is_err() {
+                recv_task.abort();
+            }
+            ("send_task", res)
+        },
+        res = (&mut recv_task) => {
+            // send_task stops on its own once it flushed everything recv_task left for it
+            if tokio::time::timeout(Duration::from_secs(5), &mut send_task).await.is_err() {
+                send_task.abort();
+            }
+            ("recv_task", res)
+        },
+        _ = ctx.sockets.closed.cancelled() => {
+            send_task.abort();
+            recv_task.abort();
+            ("shutdown", Ok(Ok(())))
+        },
+    };
+    ctx.sockets
+        .direct
+        .lock()
+        .expect("Lock not poisoned")
+        .remove(&id);
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::error!(%e, task, "Chat Task Error"),
+        Err(e) => tracing::error!(%e, "Task Join Error"),
+    }
+    leave(&room, &ctx, username).await;
+    state.exit(&name);
+}
+
+async fn send_loop<S: Socket>(
+    mut sender: SplitSink<S, Message>,
+    room: RoomState,
+    mut rx: broadcast::Receiver<MessageType>,
+    mut direct_rx: mpsc::Receiver<Message>,
+) -> Result<(), OvenauthError> {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(30)) => {
+                sender.send(Message::Ping(vec![1,2,3])).await?;
+            },
+            msg = direct_rx.recv() => {
+                // recv_task is gone once all direct senders are dropped
+                let Some(msg) = msg else {
+                    return Ok(());
+                };
+                let close = matches!(msg, Message::Close(_));
+                sender.send(msg).await?;
+                if close {
+                    return Ok(());
+                }
+            },
+            msg = rx.recv() => {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    // Whatever we missed, the history is all that's left of it
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::debug!(room = room.name, skipped, "Resyncing lagging client");
+                        metrics::counter!("ovenauth_chat_lagged_total").increment(1);
+                        let history;
+                        (rx, history) = room.subscribe().await;
+                        MessageType::Resync(history)
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                sender.send(msg.to_frame()).await?;
+            },
+        }
+    }
+}
+
+async fn recv_loop<S: Socket>(
+    mut receiver: SplitStream<S>,
+    mut conn: Connection,
+) -> Result<(), OvenauthError> {
+    while let Some(msg) = receiver.next().await {
+        let msg = match msg? {
+            Message::Text(msg) => msg,
+            Message::Binary(_) => {
+                conn.send_direct(close_frame(
+                    close_code::UNSUPPORTED,
+                    "Binary frames are not supported",
+                ))
+                .await;
+                return Ok(());
+            }
+            Message::Close(_) => return Ok(()),
+            // Ping and Pong are answered by axum
+            _ => continue,
+        };
+        let msg = match ClientMessage::parse(&msg, conn.version()) {
+            Ok(m) => m,
+            Err(e) => {
+                conn.error(ErrorCode::BadRequest, e.to_string(), None).await;
+                continue;
+            }
+        };
+        if conn.handle(msg).await.is_break() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+async fn handler(
+    ws: WebSocketUpgrade,
+    Path(room): Path<String>,
+    Extension(state): Extension<ChatState>,
+    Extension(ctx): Extension<ChatContext>,
+    State(pool): State<PgPool>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+) -> Response {
+    let valid = sqlx::query_scalar!(
+        r#"select count(*) = 1 as "f!" from users where username = $1"#,
+        &room
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap_or(false);
+    if valid {
+        let live = ChatOptions::from_username(&room, &pool)
+            .await
+            .ok()
+            .flatten()
+            .is_some_and(|o| o.live);
+        // Outlives the request, so it gets a trace of its own
+        let span = tracing::info_span!(
+            parent: None,
+            "chat",
+            %room,
+            request_id = telemetry::request_id(&headers),
+        );
+        span.follows_from(Span::current());
+        ws.on_upgrade(move |socket| {
+            handle_socket(socket, room, live, state, ctx, user.map(|e| e.0)).instrument(span)
+        })
+    } else {
+        (StatusCode::NOT_FOUND, "Chatroom not found").into_response()
+    }
+}
+
+/// Handle to the chat for the rest of the app.
+#[derive(Debug, Clone)]
+pub struct Chat {
+    state: ChatState,
+    ctx: ChatContext,
+}
+
+impl Chat {
+    /// Sets up the chat and starts its background tasks.
+    pub fn new(pool: PgPool, health: Health) -> Self {
+        let broker: Arc<dyn Broker> = match env::var("CHAT_BROKER").as_deref() {
+            Ok("postgres") => Arc::new(PgBroker::new(pool.clone())),
+            _ => Arc::new(LocalBroker),
+        };
+        let ctx = ChatContext {
+            config: ChatConfig::from_env(),
+            emotes: Arc::new(EmoteCache::from_env()),
+            pool,
+            broker,
+            node: Ulid::new(),
+            sockets: Arc::default(),
+            health,
+        };
+        let state: ChatState = Arc::default();
+        tokio::task::Builder::new()
+            .name("chat_listen")
+            .spawn(listen(state.clone(), ctx.clone()))
+            .expect("Task to be created");
+        tokio::task::Builder::new()
+            .name("chat_presence")
+            .spawn(announce_presence(state.clone(), ctx.clone()))
+            .expect("Task to be created");
+        Self { state, ctx }
+    }
+
+    pub fn routes(&self) -> Router<PgPool> {
+        Router::new()
+            .route("/:room", get(handler))
+            .layer(Extension(self.state.clone()))
+            .layer(Extension(self.ctx.clone()))
+    }
+
+    /// Rooms with sockets on this node, and how many sockets there are.
+    pub fn local_counts(&self) -> (usize, usize) {
+        self.state.local_counts()
+    }
+
+    /// Asks every client of this node to reconnect, which gets them to
+    /// another node, and waits until all of them are gone. Sockets still
+    /// open after [`CLOSE_TIMEOUT`] are dropped.
+    pub async fn shutdown(&self) {
+        let within_ms = RECONNECT_WINDOW.as_millis() as u64;
+        let reconnect = MessageType::Reconnect { within_ms }.to_frame();
+        let sockets = &self.ctx.sockets;
+        for direct in sockets.direct.lock().expect("Lock not poisoned").values() {
+            // A client too slow to take these gets dropped below
+            if let Some(direct) = direct.upgrade() {
+                let _ = direct.try_send(reconnect.clone());
+                let _ = direct.try_send(close_frame(close_code::RESTART, "Server restarting"));
+            }
+        }
+        let closed = async {
+            while self.local_counts().1 > 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        if tokio::time::timeout(CLOSE_TIMEOUT, closed).await.is_err() {
+            tracing::warn!(
+                sockets = self.local_counts().1,
+                "Dropping chat sockets that did not close"
+            );
+            sockets.closed.cancel();
+            while self.local_counts().1 > 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    /// Tells everyone in the room, on any node, that the stream went on- or
+    /// offline.
+    pub async fn set_live(&self, room: &str, live: bool) {
+        let room = self
+            .state
+            .get(room)
+            .lock()
+            .expect("Lock not poisoned")
+            .state
+            .clone();
+        let event = if live {
+            MessageType::StreamOnline
+        } else {
+            MessageType::StreamOffline
+        };
+        self.ctx.publish(&room, event).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+
+    use futures_util::future::join_all;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    /// A client that never says anything. A stalled one doesn't take any
+    /// frames either, like a connection that stopped reading.
+    struct TestSocket {
+        tx: mpsc::UnboundedSender<Message>,
+        stalled: bool,
+    }
+
+    impl Stream for TestSocket {
+        type Item = Result<Message, axum::Error>;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Pending
+        }
+    }
+
+    impl Sink<Message> for TestSocket {
+        type Error = axum::Error;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            if self.stalled {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            let _ = self.tx.send(item);
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn context() -> ChatContext {
+        ChatContext {
+            config: ChatConfig::from_env(),
+            emotes: Arc::new(EmoteCache::from_env()),
+            // Joining never touches the database
+            pool: PgPoolOptions::new()
+                .connect_lazy("postgres://localhost/ovenauth")
+                .expect("URL to be valid"),
+            broker: Arc::new(LocalBroker),
+            node: Ulid::new(),
+            sockets: Arc::default(),
+            health: Health::new(),
+        }
+    }
+
+    fn connect(
+        room: &str,
+        stalled: bool,
+        state: &ChatState,
+        ctx: &ChatContext,
+    ) -> mpsc::UnboundedReceiver<Message> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let socket = TestSocket { tx, stalled };
+        tokio::spawn(handle_socket(
+            socket,
+            room.to_string(),
+            true,
+            state.clone(),
+            ctx.clone(),
+            None,
+        ));
+        rx
+    }
+
+    /// Waits for the hello, member list and counts every join starts with.
+    async fn joined(mut rx: mpsc::UnboundedReceiver<Message>) -> bool {
+        for _ in 0..3 {
+            if rx.recv().await.is_none() {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn stalled_join_does_not_block_others() {
+        let state = ChatState::default();
+        let ctx = context();
+        // Something to replay to the clients joining room a
+        let room = state
+            .get("a")
+            .lock()
+            .expect("Lock not poisoned")
+            .state
+            .clone();
+        for i in 0..BUFFERSIZE {
+            let msg = OutgoingMessage {
+                message_id: Ulid::new(),
+                content: format!("message {i}"),
+                emotes: Vec::new(),
+                author: "alice".to_string(),
+                timestamp: chrono::Utc::now(),
+                reply_to: None,
+                reply: None,
+                edited_at: None,
+                revisions: Vec::new(),
+            };
+            room.broadcast(MessageType::Msg(msg)).await;
+        }
+
+        let _stalled = connect("a", true, &state, &ctx);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let joins: Vec<_> = (0..200)
+            .map(|i| connect(if i % 2 == 0 { "b" } else { "a" }, false, &state, &ctx))
+            .collect();
+        let joined = tokio::time::timeout(
+            Duration::from_secs(2),
+            join_all(joins.into_iter().map(joined)),
+        )
+        .await
+        .expect("Joins to finish while another client stalls");
+        assert!(joined.into_iter().all(|joined| joined));
+        assert_eq!(state.local_counts(), (2, 201));
+    }
+
+    /// Can't subscribe until the database is back.
+    #[derive(Debug)]
+    struct FlakyBroker {
+        attempts: AtomicUsize,
+        envelope: Envelope,
+    }
+
+    #[async_trait::async_trait]
+    impl Broker for FlakyBroker {
+        async fn publish(&self, _envelope: &Envelope) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn subscribe(&self) -> anyhow::Result<mpsc::Receiver<Envelope>> {
+            if self.attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                anyhow::bail!("Connection refused");
+            }
+            let (tx, rx) = mpsc::channel(1);
+            tx.send(self.envelope.clone()).await?;
+            Ok(rx)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscribes_once_the_broker_is_back() {
+        let broker = Arc::new(FlakyBroker {
+            attempts: AtomicUsize::new(0),
+            envelope: Envelope {
+                node: Ulid::new(),
+                room: "alice".to_string(),
+                event: BrokerEvent::Event(MessageType::Mute {
+                    username: "bob".to_string(),
+                    until: None,
+                }),
+            },
+        });
+        let ctx = ChatContext {
+            broker: broker.clone(),
+            ..context()
+        };
+        let state = ChatState::default();
+        listen(state.clone(), ctx).await;
+        assert_eq!(broker.attempts.load(Ordering::Relaxed), 3);
+        let room = state.get("alice").lock().unwrap().state.clone();
+        assert!(room.muted.read().await.contains_key("bob"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_drops_stalled_clients() {
+        let state = ChatState::default();
+        let ctx = context();
+        let chat = Chat {
+            state: state.clone(),
+            ctx: ctx.clone(),
+        };
+        let _stalled = connect("a", true, &state, &ctx);
+        let mut client = connect("a", false, &state, &ctx);
+        for _ in 0..3 {
+            client.recv().await.unwrap();
+        }
+        assert_eq!(state.local_counts(), (1, 2));
+
+        let started = tokio::time::Instant::now();
+        chat.shutdown().await;
+        assert!(started.elapsed() < CLOSE_TIMEOUT + Duration::from_secs(1));
+        assert_eq!(state.local_counts(), (0, 0));
+        let Some(Message::Text(reconnect)) = client.recv().await else {
+            panic!("Expected a reconnect message");
+        };
+        assert!(reconnect.contains("reconnect"), "{reconnect}");
+        assert!(matches!(client.recv().await, Some(Message::Close(_))));
+    }
+}
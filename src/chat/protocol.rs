@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+
+use axum::extract::ws::{CloseFrame, Message};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::emotes::EmotePosition;
+
+/// Bumped whenever a change to [`ClientMessage`] or [`MessageType`] is not
+/// backwards compatible.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client version we still talk to. Clients that never send a
+/// [`ClientMessage::Hello`] are treated as version 0 and may only send the
+/// untagged [`IncomingMessage`].
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+pub enum MessageType {
+    Hello {
+        version: u32,
+        min_version: u32,
+    },
+    Join(String),
+    Leave(String),
+    Msg(OutgoingMessage),
+    Connect(HashSet<String>),
+    Error {
+        code: ErrorCode,
+        message: String,
+        r#ref: Option<Ulid>,
+    },
+    Delete(Ulid),
+    Edit {
+        message_id: Ulid,
+        content: String,
+        emotes: Vec<EmotePosition>,
+        edited_at: DateTime<Utc>,
+    },
+    Typing(String),
+    History(Vec<OutgoingMessage>),
+    Revisions {
+        message_id: Ulid,
+        revisions: Vec<Revision>,
+    },
+    Mute {
+        username: String,
+        until: Option<DateTime<Utc>>,
+    },
+    Unmute(String),
+    Clear,
+    /// The whole history, replacing everything the client has, after it fell
+    /// too far behind
+    Resync(Vec<OutgoingMessage>),
+    Presence(PresenceCounts),
+    #[serde(rename = "stream_online")]
+    StreamOnline,
+    #[serde(rename = "stream_offline")]
+    StreamOffline,
+    /// The node is shutting down and closes the connection right after.
+    /// Clients reconnect after a random delay of up to `within_ms`, so they
+    /// don't all hit the remaining nodes at once.
+    Reconnect {
+        within_ms: u64,
+    },
+}
+
+impl MessageType {
+    pub fn error(code: ErrorCode, message: impl Into<String>, r#ref: Option<Ulid>) -> Self {
+        Self::Error {
+            code,
+            message: message.into(),
+            r#ref,
+        }
+    }
+
+    pub fn to_frame(&self) -> Message {
+        Message::Text(serde_json::to_string(self).expect("serialization to work"))
+    }
+}
+
+/// Recoverable problems with a single client frame. These are reported back
+/// to the sender only and never close the connection.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    InvalidMessage,
+    RateLimited,
+    Unauthenticated,
+    Forbidden,
+    Muted,
+    UnknownMessage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingMessage {
+    pub message_id: Ulid,
+    pub content: String,
+    pub emotes: Vec<EmotePosition>,
+    pub author: String,
+    pub timestamp: DateTime<Utc>,
+    pub reply_to: Option<Ulid>,
+    /// Snapshot of the `reply_to` message, so clients don't need it in their history
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply: Option<ReplySnippet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Previous versions of `content`, oldest first. Only moderators get to see them.
+    #[serde(skip)]
+    pub revisions: Vec<Revision>,
+}
+
+/// Everyone connected to a room on any node. Logged in users count once no
+/// matter how many connections they have.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceCounts {
+    pub total: usize,
+    pub authenticated: usize,
+    pub anonymous: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplySnippet {
+    pub message_id: Ulid,
+    pub author: String,
+    pub content: String,
+}
+
+impl ReplySnippet {
+    const MAX_LENGTH: usize = 100;
+
+    pub fn new(parent: &OutgoingMessage) -> Self {
+        let content = match parent.content.char_indices().nth(Self::MAX_LENGTH) {
+            Some((end, _)) => format!("{}…", &parent.content[..end]),
+            None => parent.content.clone(),
+        };
+        Self {
+            message_id: parent.message_id,
+            author: parent.author.clone(),
+            content,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IncomingMessage {
+    pub content: String,
+    pub reply_to: Option<Ulid>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+pub enum ClientMessage {
+    Hello {
+        version: u32,
+    },
+    Send(IncomingMessage),
+    Delete(Ulid),
+    Edit {
+        message_id: Ulid,
+        content: String,
+    },
+    Typing,
+    History {
+        before: Option<Ulid>,
+        limit: Option<usize>,
+    },
+    Revisions(Ulid),
+    Mod(ModCommand),
+}
+
+impl ClientMessage {
+    /// Parses a text frame. Legacy clients that did not negotiate a version
+    /// send a bare [`IncomingMessage`].
+    pub fn parse(frame: &str, version: u32) -> serde_json::Result<Self> {
+        match serde_json::from_str(frame) {
+            Err(_) if version == 0 => serde_json::from_str(frame).map(Self::Send),
+            res => res,
+        }
+    }
+}
+
+/// Commands only the room owner may send.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum ModCommand {
+    Mute {
+        username: String,
+        /// Mute duration in seconds, forever if missing
+        duration: Option<u64>,
+    },
+    Unmute {
+        username: String,
+    },
+    Clear,
+}
+
+pub fn close_frame(code: u16, reason: &'static str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
@@ -0,0 +1,452 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock as StdRwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::{broadcast, RwLock};
+use tokio::time::Instant;
+use ulid::Ulid;
+
+use super::broker::BrokerEvent;
+use super::protocol::{MessageType, OutgoingMessage, PresenceCounts, Revision};
+use super::{BUFFERSIZE, REMOTE_HISTORY_RETENTION};
+
+pub type MessageBuffer = Arc<RwLock<VecDeque<OutgoingMessage>>>;
+/// Muted usernames, with `None` meaning until unmuted.
+pub type MuteList = Arc<RwLock<HashMap<String, Option<DateTime<Utc>>>>>;
+
+/// The parts of a room every connection keeps a handle to.
+#[derive(Debug, Clone)]
+pub struct RoomState {
+    pub name: String,
+    pub tx: broadcast::Sender<MessageType>,
+    pub messagebuffer: MessageBuffer,
+    pub muted: MuteList,
+    /// Whether the owner is currently streaming
+    pub live: Arc<AtomicBool>,
+}
+
+impl RoomState {
+    /// Updates the room for `event` and sends it to all local clients, no
+    /// matter which node it originated from.
+    pub async fn broadcast(&self, event: MessageType) {
+        // Held until the event is sent, so subscribe never sees it twice
+        let mut msgbuff = self.messagebuffer.write().await;
+        match &event {
+            MessageType::Msg(msg) => {
+                while msgbuff.len() >= BUFFERSIZE {
+                    msgbuff.pop_front();
+                }
+                msgbuff.push_back(msg.clone());
+            }
+            MessageType::Delete(message_id) => {
+                msgbuff.retain(|m| m.message_id != *message_id);
+            }
+            MessageType::Edit {
+                message_id,
+                content,
+                emotes,
+                edited_at,
+            } => {
+                if let Some(m) = msgbuff.iter_mut().find(|m| m.message_id == *message_id) {
+                    let previous = std::mem::replace(&mut m.content, content.clone());
+                    m.revisions.push(Revision {
+                        content: previous,
+                        timestamp: m.edited_at.unwrap_or(m.timestamp),
+                    });
+                    m.emotes = emotes.clone();
+                    m.edited_at = Some(*edited_at);
+                }
+            }
+            MessageType::Mute { username, until } => {
+                self.muted.write().await.insert(username.clone(), *until);
+            }
+            MessageType::Unmute(username) => {
+                self.muted.write().await.remove(username);
+            }
+            MessageType::Clear => msgbuff.clear(),
+            MessageType::StreamOnline => self.live.store(true, Ordering::Relaxed),
+            MessageType::StreamOffline => self.live.store(false, Ordering::Relaxed),
+            _ => {}
+        }
+        let _ = self.tx.send(event);
+    }
+
+    /// Subscribes to the room, along with the history up to that point.
+    pub async fn subscribe(&self) -> (broadcast::Receiver<MessageType>, Vec<OutgoingMessage>) {
+        let msgbuff = self.messagebuffer.read().await;
+        (self.tx.subscribe(), msgbuff.iter().cloned().collect())
+    }
+}
+
+/// All rooms with someone in them on any node. The map is only locked to
+/// look rooms up, each room has its own lock. Rooms are dropped once idle.
+#[derive(Debug, Default)]
+pub struct Rooms {
+    rooms: StdRwLock<HashMap<String, Arc<Mutex<Room>>>>,
+    /// Kept apart from the rooms, so that mutes outlive them
+    mutes: Mutex<HashMap<String, MuteList>>,
+}
+
+impl Rooms {
+    /// Registers a new connection to the room, so it doesn't get evicted.
+    /// `live` is only taken for a new room, events keep it current after that.
+    pub fn enter(&self, name: &str, live: bool) -> Arc<Mutex<Room>> {
+        // Holding either map lock keeps the room from being evicted under us
+        if let Some(room) = self.rooms.read().expect("Lock not poisoned").get(name) {
+            room.lock().expect("Lock not poisoned").connections += 1;
+            return room.clone();
+        }
+        let mut rooms = self.rooms.write().expect("Lock not poisoned");
+        let room = rooms.entry(name.to_string()).or_insert_with(|| {
+            let room = Room::new(name.to_string(), self.muted(name));
+            room.state.live.store(live, Ordering::Relaxed);
+            Arc::new(Mutex::new(room))
+        });
+        room.lock().expect("Lock not poisoned").connections += 1;
+        room.clone()
+    }
+
+    /// Counterpart to [`Rooms::enter`], evicting the room if this was the last
+    /// connection to it.
+    pub fn exit(&self, name: &str) {
+        let mut rooms = self.rooms.write().expect("Lock not poisoned");
+        let Some(room) = rooms.get(name) else {
+            return;
+        };
+        let idle = {
+            let mut room = room.lock().expect("Lock not poisoned");
+            room.connections -= 1;
+            room.is_idle()
+        };
+        if idle {
+            rooms.remove(name);
+        }
+    }
+
+    /// Drops rooms nobody is in and nothing happened in for a while, and
+    /// mute lists with nothing left in them.
+    pub fn evict_idle(&self) {
+        self.rooms
+            .write()
+            .expect("Lock not poisoned")
+            .retain(|_, room| !room.lock().expect("Lock not poisoned").is_idle());
+        let now = Utc::now();
+        self.mutes
+            .lock()
+            .expect("Lock not poisoned")
+            .retain(|_, muted| {
+                // Still in use by a room, or busy
+                if Arc::strong_count(muted) > 1 {
+                    return true;
+                }
+                let Ok(mut muted) = muted.try_write() else {
+                    return true;
+                };
+                muted.retain(|_, until| until.is_none_or(|until| until > now));
+                !muted.is_empty()
+            });
+    }
+
+    /// The room's mutes, which are kept even while nobody is in the room.
+    fn muted(&self, name: &str) -> MuteList {
+        self.mutes
+            .lock()
+            .expect("Lock not poisoned")
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn get(&self, name: &str) -> Arc<Mutex<Room>> {
+        if let Some(room) = self.rooms.read().expect("Lock not poisoned").get(name) {
+            return room.clone();
+        }
+        self.rooms
+            .write()
+            .expect("Lock not poisoned")
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(Room::new(name.to_string(), self.muted(name)))))
+            .clone()
+    }
+
+    /// Rooms with sockets on this node, and how many sockets there are.
+    pub fn local_counts(&self) -> (usize, usize) {
+        let rooms = self.rooms.read().expect("Lock not poisoned");
+        rooms
+            .values()
+            .map(|room| room.lock().expect("Lock not poisoned").connections)
+            .filter(|&connections| connections > 0)
+            .fold((0, 0), |(rooms, total), connections| {
+                (rooms + 1, total + connections)
+            })
+    }
+
+    pub fn all(&self) -> Vec<(String, Arc<Mutex<Room>>)> {
+        self.rooms
+            .read()
+            .expect("Lock not poisoned")
+            .iter()
+            .map(|(name, room)| (name.clone(), room.clone()))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Room {
+    pub state: RoomState,
+    /// Connection count of every logged in user on this node
+    users: HashMap<String, usize>,
+    /// Anonymous connections on this node
+    anonymous: usize,
+    /// What other nodes reported in this room, and when they last did
+    remote: HashMap<Ulid, RemotePresence>,
+    /// When another node last sent an event, the history is kept for a while
+    /// so clients joining here still get it
+    remote_activity: Option<Instant>,
+    /// Open sockets on this node, logged in or not
+    connections: usize,
+    /// Counts local clients were last told about
+    counts: PresenceCounts,
+    /// Anonymous count other nodes were last told about
+    forwarded_anonymous: usize,
+}
+
+#[derive(Debug)]
+struct RemotePresence {
+    seen: Instant,
+    users: HashSet<String>,
+    anonymous: usize,
+}
+
+impl Room {
+    pub fn new(name: String, muted: MuteList) -> Self {
+        Room {
+            state: RoomState {
+                name,
+                tx: broadcast::channel(100).0,
+                messagebuffer: Arc::new(RwLock::new(VecDeque::with_capacity(BUFFERSIZE))),
+                muted,
+                live: Arc::new(AtomicBool::new(false)),
+            },
+            users: HashMap::new(),
+            anonymous: 0,
+            remote: HashMap::new(),
+            remote_activity: None,
+            connections: 0,
+            counts: PresenceCounts::default(),
+            forwarded_anonymous: 0,
+        }
+    }
+
+    /// Nobody is watching this room anywhere we know of, and its history is
+    /// not worth keeping.
+    fn is_idle(&self) -> bool {
+        self.connections == 0
+            && self.remote.is_empty()
+            && self
+                .remote_activity
+                .is_none_or(|at| at.elapsed() >= REMOTE_HISTORY_RETENTION)
+    }
+
+    /// Another node sent an event to the room.
+    pub fn remote_event(&mut self) {
+        self.remote_activity = Some(Instant::now());
+    }
+
+    /// What other nodes need to know about the clients on this node.
+    pub fn local_presence(&mut self) -> BrokerEvent {
+        self.forwarded_anonymous = self.anonymous;
+        BrokerEvent::Presence {
+            users: self.users.keys().cloned().collect(),
+            anonymous: self.anonymous,
+        }
+    }
+
+    pub fn has_local_clients(&self) -> bool {
+        !self.users.is_empty() || self.anonymous > 0
+    }
+
+    /// Whether other nodes have an outdated anonymous count.
+    pub fn anonymous_changed(&self) -> bool {
+        self.anonymous != self.forwarded_anonymous
+    }
+
+    /// Logged in users connected to any node.
+    pub fn members(&self) -> HashSet<String> {
+        let mut members: HashSet<_> = self.users.keys().cloned().collect();
+        for remote in self.remote.values() {
+            members.extend(remote.users.iter().cloned());
+        }
+        members
+    }
+
+    pub fn counts(&self) -> PresenceCounts {
+        let authenticated = self.members().len();
+        let anonymous = self.anonymous + self.remote.values().map(|r| r.anonymous).sum::<usize>();
+        PresenceCounts {
+            total: authenticated + anonymous,
+            authenticated,
+            anonymous,
+        }
+    }
+
+    /// Returns the counts if they changed since the last call, so clients
+    /// get them at most once per call.
+    pub fn take_counts(&mut self) -> Option<PresenceCounts> {
+        let counts = self.counts();
+        if counts == self.counts {
+            return None;
+        }
+        self.counts = counts;
+        Some(counts)
+    }
+
+    /// Runs `f` and tells local clients about everyone who joined or left
+    /// the room across all nodes because of it.
+    fn update_presence<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let before = self.members();
+        let res = f(self);
+        let after = self.members();
+        for u in after.difference(&before) {
+            let _ = self.state.tx.send(MessageType::Join(u.clone()));
+        }
+        for u in before.difference(&after) {
+            let _ = self.state.tx.send(MessageType::Leave(u.clone()));
+        }
+        res
+    }
+
+    /// Returns whether this is a logged in user's first connection on this
+    /// node.
+    pub fn join(&mut self, username: Option<&str>) -> bool {
+        let Some(username) = username else {
+            self.anonymous += 1;
+            return false;
+        };
+        self.update_presence(|room| {
+            let c = room.users.entry(username.to_string()).or_insert(0);
+            *c += 1;
+            *c == 1
+        })
+    }
+
+    /// Returns whether this was a logged in user's last connection on this
+    /// node.
+    pub fn leave(&mut self, username: Option<&str>) -> bool {
+        let Some(username) = username else {
+            self.anonymous -= 1;
+            return false;
+        };
+        self.update_presence(|room| {
+            let c = room
+                .users
+                .get_mut(username)
+                .expect("User to exist in room before he leaves");
+            *c -= 1;
+            if *c == 0 {
+                room.users.remove(username);
+                return true;
+            }
+            false
+        })
+    }
+
+    pub fn set_remote(&mut self, node: Ulid, users: HashSet<String>, anonymous: usize) {
+        self.update_presence(|room| {
+            if users.is_empty() && anonymous == 0 {
+                room.remote.remove(&node);
+                return;
+            }
+            let remote = RemotePresence {
+                seen: Instant::now(),
+                users,
+                anonymous,
+            };
+            room.remote.insert(node, remote);
+        })
+    }
+
+    /// Forgets nodes that did not report their presence for `max_age`.
+    pub fn expire_remote(&mut self, max_age: Duration) {
+        self.update_presence(|room| {
+            room.remote
+                .retain(|_, remote| remote.seen.elapsed() < max_age)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(rooms: &Rooms, name: &str) -> RoomState {
+        rooms.get(name).lock().unwrap().state.clone()
+    }
+
+    #[tokio::test]
+    async fn mutes_outlive_rooms() {
+        let rooms = Rooms::default();
+        rooms.enter("alice", false);
+        let mute = |username: &str| MessageType::Mute {
+            username: username.to_string(),
+            until: None,
+        };
+        state(&rooms, "alice").broadcast(mute("bob")).await;
+        rooms.exit("alice");
+        rooms.evict_idle();
+        assert!(rooms.all().is_empty());
+
+        let muted = state(&rooms, "alice").muted;
+        assert!(muted.read().await.contains_key("bob"));
+
+        // Nothing to keep once everyone is unmuted
+        let room = state(&rooms, "alice");
+        room.broadcast(MessageType::Unmute("bob".to_string())).await;
+        drop((room, muted));
+        rooms.evict_idle();
+        assert!(rooms.all().is_empty());
+        assert!(rooms.mutes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_history_of_remote_rooms() {
+        let rooms = Rooms::default();
+        rooms.get("alice").lock().unwrap().remote_event();
+        let message = OutgoingMessage {
+            message_id: Ulid::new(),
+            content: "hi".to_string(),
+            emotes: Vec::new(),
+            author: "bob".to_string(),
+            timestamp: Utc::now(),
+            reply_to: None,
+            reply: None,
+            edited_at: None,
+            revisions: Vec::new(),
+        };
+        state(&rooms, "alice")
+            .broadcast(MessageType::Msg(message))
+            .await;
+        rooms.evict_idle();
+        let (_, history) = state(&rooms, "alice").subscribe().await;
+        assert_eq!(history.len(), 1);
+
+        tokio::time::advance(REMOTE_HISTORY_RETENTION).await;
+        rooms.evict_idle();
+        assert!(rooms.all().is_empty());
+    }
+
+    #[tokio::test]
+    async fn live_is_only_taken_for_new_rooms() {
+        let rooms = Rooms::default();
+        rooms.enter("alice", true);
+        assert!(state(&rooms, "alice").live.load(Ordering::Relaxed));
+        state(&rooms, "alice")
+            .broadcast(MessageType::StreamOffline)
+            .await;
+        // Read before the stream went offline
+        rooms.enter("alice", true);
+        assert!(!state(&rooms, "alice").live.load(Ordering::Relaxed));
+    }
+}
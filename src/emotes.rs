@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fmt};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+const DEFAULT_SEVENTV_API: &str = "https://7tv.io/v3";
+const GLOBAL_SET: &str = "global";
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Source of emote sets, by 7TV emote set id.
+#[async_trait]
+pub trait EmoteProvider: fmt::Debug + Send + Sync {
+    async fn emote_set(&self, set_id: &str) -> Result<EmoteSet>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Emote {
+    pub id: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EmoteSet {
+    emotes: HashMap<String, Emote>,
+}
+
+// Just the parts of the 7TV v3 emote set we care about
+#[derive(Debug, Deserialize)]
+struct SevenTvSet {
+    #[serde(default)]
+    emotes: Vec<SevenTvEmote>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SevenTvEmote {
+    id: String,
+    name: String,
+    data: SevenTvEmoteData,
+}
+
+#[derive(Debug, Deserialize)]
+struct SevenTvEmoteData {
+    host: SevenTvHost,
+}
+
+#[derive(Debug, Deserialize)]
+struct SevenTvHost {
+    url: String,
+}
+
+impl From<SevenTvSet> for EmoteSet {
+    fn from(set: SevenTvSet) -> Self {
+        let emotes = set
+            .emotes
+            .into_iter()
+            .map(|e| {
+                (
+                    e.name,
+                    Emote {
+                        id: e.id,
+                        url: e.data.host.url,
+                    },
+                )
+            })
+            .collect();
+        Self { emotes }
+    }
+}
+
+/// Where an emote occurs in a message. Offsets count unicode code points,
+/// `end` is exclusive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmotePosition {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl EmoteSet {
+    /// Adds all emotes of `other` that don't exist in `self` yet.
+    fn extend(&mut self, other: EmoteSet) {
+        for (name, emote) in other.emotes {
+            self.emotes.entry(name).or_insert(emote);
+        }
+    }
+
+    pub fn tokenize(&self, content: &str) -> Vec<EmotePosition> {
+        let mut positions = Vec::new();
+        let mut word_start = None;
+        // Trailing space so the last word gets terminated as well
+        for (i, (pos, c)) in content
+            .char_indices()
+            .chain([(content.len(), ' ')])
+            .enumerate()
+        {
+            match (c.is_whitespace(), word_start) {
+                (false, None) => word_start = Some((i, pos)),
+                (true, Some((start, byte_start))) => {
+                    let word = &content[byte_start..pos];
+                    if let Some(emote) = self.emotes.get(word) {
+                        positions.push(EmotePosition {
+                            id: emote.id.clone(),
+                            name: word.to_string(),
+                            url: emote.url.clone(),
+                            start,
+                            end: i,
+                        });
+                    }
+                    word_start = None;
+                }
+                _ => {}
+            }
+        }
+        positions
+    }
+}
+
+/// Fetches emote sets from the 7TV API.
+#[derive(Debug)]
+pub struct SevenTv {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl SevenTv {
+    pub fn new(base_url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("Client to be built");
+        Self { client, base_url }
+    }
+}
+
+#[async_trait]
+impl EmoteProvider for SevenTv {
+    async fn emote_set(&self, set_id: &str) -> Result<EmoteSet> {
+        let set: SevenTvSet = self
+            .client
+            .get(format!("{}/emote-sets/{set_id}", self.base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(set.into())
+    }
+}
+
+/// Reads emote sets in the 7TV format from `<dir>/<set_id>.json`, for local
+/// development and tests.
+#[derive(Debug)]
+pub struct FixtureProvider {
+    dir: PathBuf,
+}
+
+impl FixtureProvider {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+#[async_trait]
+impl EmoteProvider for FixtureProvider {
+    async fn emote_set(&self, set_id: &str) -> Result<EmoteSet> {
+        // Ids come from users, they must not lead out of the directory
+        if set_id.contains(['/', '\\']) || set_id.contains("..") {
+            bail!("Invalid emote set id {set_id}");
+        }
+        let path = self.dir.join(format!("{set_id}.json"));
+        let set: SevenTvSet = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        Ok(set.into())
+    }
+}
+
+/// Emotes usable in each chat room, i.e. the room owner's emote set plus the
+/// global set, cached for [`CACHE_TTL`].
+#[derive(Debug)]
+pub struct EmoteCache {
+    provider: Arc<dyn EmoteProvider>,
+    rooms: RwLock<HashMap<String, (Instant, Arc<EmoteSet>)>>,
+}
+
+impl EmoteCache {
+    pub fn new(provider: Arc<dyn EmoteProvider>) -> Self {
+        Self {
+            provider,
+            rooms: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Uses the fixtures in `EMOTE_FIXTURE_DIR` if set, the 7TV API at
+    /// `SEVENTV_API_URL` otherwise.
+    pub fn from_env() -> Self {
+        let provider: Arc<dyn EmoteProvider> = match env::var("EMOTE_FIXTURE_DIR") {
+            Ok(dir) => Arc::new(FixtureProvider::new(dir.into())),
+            Err(_) => Arc::new(SevenTv::new(
+                env::var("SEVENTV_API_URL").unwrap_or_else(|_| DEFAULT_SEVENTV_API.to_string()),
+            )),
+        };
+        Self::new(provider)
+    }
+
+    pub async fn for_room(&self, room: &str, pool: &PgPool) -> Arc<EmoteSet> {
+        if let Some((fetched, set)) = self.rooms.read().await.get(room) {
+            if fetched.elapsed() < CACHE_TTL {
+                return set.clone();
+            }
+        }
+        let set = Arc::new(self.fetch(room, pool).await);
+        self.rooms
+            .write()
+            .await
+            .insert(room.to_string(), (Instant::now(), set.clone()));
+        set
+    }
+
+    /// Forgets sets older than [`CACHE_TTL`], they would be fetched again
+    /// anyway.
+    pub async fn evict_expired(&self) {
+        self.rooms
+            .write()
+            .await
+            .retain(|_, (fetched, _)| fetched.elapsed() < CACHE_TTL);
+    }
+
+    // Failures are cached like empty sets, so a broken API doesn't get hit for every message
+    async fn fetch(&self, room: &str, pool: &PgPool) -> EmoteSet {
+        let set_id = match sqlx::query_scalar!(
+            "select o.emote_id from options o, users u where o.user_id = u.id and u.username = $1",
+            room
+        )
+        .fetch_optional(pool)
+        .await
+        {
+            Ok(set_id) => set_id.flatten(),
+            Err(e) => {
+                tracing::error!(%e, %room, "Failed to look up emote set");
+                None
+            }
+        };
+        self.load(room, set_id.as_deref()).await
+    }
+
+    /// The room's emote set, if it has one, with the global emotes it doesn't
+    /// override.
+    async fn load(&self, room: &str, set_id: Option<&str>) -> EmoteSet {
+        let mut set = EmoteSet::default();
+        if let Some(set_id) = set_id {
+            match self.provider.emote_set(set_id).await {
+                Ok(room_set) => set = room_set,
+                Err(e) => tracing::warn!(%e, %room, %set_id, "Failed to fetch emote set"),
+            }
+        }
+        match self.provider.emote_set(GLOBAL_SET).await {
+            Ok(global) => set.extend(global),
+            Err(e) => tracing::warn!(%e, "Failed to fetch global emote set"),
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    fn set(names: &[(&str, &str)]) -> EmoteSet {
+        let emotes = names
+            .iter()
+            .map(|&(name, id)| {
+                let emote = Emote {
+                    id: id.to_string(),
+                    url: format!("//cdn.7tv.app/emote/{id}"),
+                };
+                (name.to_string(), emote)
+            })
+            .collect();
+        EmoteSet { emotes }
+    }
+
+    /// `(id, start, end)` of every emote found
+    fn spans(set: &EmoteSet, content: &str) -> Vec<(String, usize, usize)> {
+        set.tokenize(content)
+            .into_iter()
+            .map(|p| (p.id, p.start, p.end))
+            .collect()
+    }
+
+    fn fixtures() -> EmoteCache {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        EmoteCache::new(Arc::new(FixtureProvider::new(dir)))
+    }
+
+    #[test]
+    fn offsets_count_code_points() {
+        let set = set(&[("Pog", "pog")]);
+        assert_eq!(
+            spans(&set, "héllo Pog 😀 Pog"),
+            [("pog".into(), 6, 9), ("pog".into(), 12, 15)]
+        );
+        assert_eq!(spans(&set, "日本語 Pog"), [("pog".into(), 4, 7)]);
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_skipped() {
+        let set = set(&[("Pog", "pog")]);
+        assert_eq!(spans(&set, "  Pog\t"), [("pog".into(), 2, 5)]);
+        assert_eq!(spans(&set, "\n Pog  "), [("pog".into(), 2, 5)]);
+        assert!(spans(&set, "   ").is_empty());
+        assert!(spans(&set, "").is_empty());
+    }
+
+    #[test]
+    fn adjacent_emotes() {
+        let set = set(&[("Pog", "pog"), ("KEKW", "kekw")]);
+        assert_eq!(
+            spans(&set, "Pog KEKW  Pog"),
+            [
+                ("pog".into(), 0, 3),
+                ("kekw".into(), 4, 8),
+                ("pog".into(), 10, 13)
+            ]
+        );
+        // Emotes are whole words only
+        assert!(spans(&set, "PogKEKW xPog Pogs").is_empty());
+    }
+
+    #[test]
+    fn room_set_takes_priority() {
+        let mut room = set(&[("Pog", "room-pog")]);
+        room.extend(set(&[("Pog", "global-pog"), ("KEKW", "global-kekw")]));
+        assert_eq!(
+            spans(&room, "Pog KEKW"),
+            [("room-pog".into(), 0, 3), ("global-kekw".into(), 4, 8)]
+        );
+    }
+
+    #[tokio::test]
+    async fn loads_room_and_global_fixtures() {
+        let set = fixtures().load("alice", Some("room")).await;
+        let emotes = set.tokenize("Wave Pog KEKW");
+        let ids: Vec<_> = emotes.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["room-wave", "room-pog", "global-kekw"]);
+        assert_eq!(emotes[1].url, "//cdn.7tv.app/emote/room-pog");
+
+        // A missing room set still leaves the global emotes
+        let set = fixtures().load("alice", Some("missing")).await;
+        let ids: Vec<_> = set.tokenize("Wave Pog").into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["global-pog"]);
+    }
+
+    #[tokio::test]
+    async fn caches_rooms() {
+        // Nothing listens there, so the room falls back to the global set
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://127.0.0.1:1/ovenauth")
+            .expect("URL to be valid");
+        let cache = fixtures();
+        let first = cache.for_room("alice", &pool).await;
+        let ids: Vec<_> = first.tokenize("Pog").into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["global-pog"]);
+        let second = cache.for_room("alice", &pool).await;
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_expired_rooms() {
+        let cache = fixtures();
+        cache
+            .rooms
+            .write()
+            .await
+            .insert("alice".to_string(), (Instant::now(), Arc::default()));
+        cache.evict_expired().await;
+        assert_eq!(cache.rooms.read().await.len(), 1);
+        tokio::time::advance(CACHE_TTL).await;
+        cache.evict_expired().await;
+        assert!(cache.rooms.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fixture_ids_stay_in_the_directory() {
+        let provider = fixtures().provider;
+        assert!(provider.emote_set("global").await.is_ok());
+        assert!(provider.emote_set("../fixtures/global").await.is_err());
+        assert!(provider.emote_set("..").await.is_err());
+    }
+}
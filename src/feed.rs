@@ -0,0 +1,370 @@
+use std::{collections::HashMap, convert::Infallible, time::Duration};
+
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    response::sse::{self, KeepAlive, Sse},
+    routing::get,
+    Extension, Router,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{future::join_all, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+use crate::{error::OvenauthError, health::Health, ome::Ome};
+
+const CHANNEL: &str = "ovenauth_stream_events";
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// OME stats are cached for 5 seconds anyway
+const VIEWERS_INTERVAL: Duration = Duration::from_secs(5);
+/// How far back clients can resume
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+/// Most events a resuming client is sent
+const MAX_MISSED: i64 = 1000;
+/// Doubled after every failed attempt to listen for events
+const LISTEN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_LISTEN_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A stream status change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StreamEvent {
+    Title {
+        name: Option<String>,
+    },
+    Online {
+        protocol: String,
+        started_at: DateTime<Utc>,
+    },
+    Offline {
+        ended_at: DateTime<Utc>,
+    },
+    /// Only sent live, a resuming client gets the next one anyway
+    Viewers {
+        viewers: u64,
+    },
+}
+
+impl StreamEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Title { .. } => "title",
+            Self::Online { .. } => "online",
+            Self::Offline { .. } => "offline",
+            Self::Viewers { .. } => "viewers",
+        }
+    }
+}
+
+/// Which events a client gets.
+#[derive(Debug, Clone)]
+enum Filter {
+    All,
+    Public,
+    Stream(String),
+}
+
+impl Filter {
+    fn matches(&self, event: &Published) -> bool {
+        match self {
+            Self::All => true,
+            Self::Public => event.public,
+            Self::Stream(stream) => &event.stream == stream,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Published {
+    /// `None` for events that are not stored
+    id: Option<i64>,
+    stream: String,
+    public: bool,
+    event: StreamEvent,
+}
+
+impl Published {
+    fn to_sse(&self) -> sse::Event {
+        let mut data = json!(self.event);
+        data["stream"] = json!(self.stream);
+        let event = sse::Event::default()
+            .event(self.event.name())
+            .data(data.to_string());
+        match self.id {
+            Some(id) => event.id(id.to_string()),
+            None => event,
+        }
+    }
+
+    /// Stored events after `id`.
+    async fn since(id: i64, filter: &Filter, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        let (public, stream) = match filter {
+            Filter::All => (false, None),
+            Filter::Public => (true, None),
+            Filter::Stream(stream) => (false, Some(stream)),
+        };
+        let rows = sqlx::query!(
+            r#"--sql
+            select id, stream, public, event
+            from stream_events
+            where id > $1 and (not $2 or public) and ($3::text is null or stream = $3)
+            order by id
+            limit $4
+            "#,
+            id,
+            public,
+            stream,
+            MAX_MISSED
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(Self {
+                    id: Some(row.id),
+                    stream: row.stream,
+                    public: row.public,
+                    event: serde_json::from_value(row.event).ok()?,
+                })
+            })
+            .collect())
+    }
+}
+
+/// Stores the event and tells every instance about it.
+pub async fn publish(user_id: i32, event: &StreamEvent, pool: &PgPool) -> anyhow::Result<()> {
+    let id = sqlx::query_scalar!(
+        r#"--sql
+        insert into stream_events (stream, public, event)
+        select u.username, o.public and not u.hidden, $2
+        from users u
+        join options o on o.user_id = u.id
+        where u.id = $1
+        returning id
+        "#,
+        user_id,
+        json!(event)
+    )
+    .fetch_one(pool)
+    .await?;
+    // query! can't describe the void pg_notify returns
+    sqlx::query("select pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(id.to_string())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Stream status changes for the SSE endpoints.
+#[derive(Debug, Clone)]
+pub struct Feed {
+    pool: PgPool,
+    tx: broadcast::Sender<Published>,
+}
+
+impl Feed {
+    pub fn new(pool: PgPool, ome: Ome) -> Self {
+        let (tx, _) = broadcast::channel(256);
+        let feed = Self { pool, tx };
+        tokio::spawn(feed.clone().listen());
+        tokio::spawn(feed.clone().poll_viewers(ome));
+        feed
+    }
+
+    async fn connect(&self) -> sqlx::Result<PgListener> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANNEL).await?;
+        Ok(listener)
+    }
+
+    /// Forwards stored events from every instance to our clients. Keeps
+    /// trying to connect, without it clients get no events at all.
+    async fn listen(self) {
+        let mut backoff = LISTEN_BACKOFF;
+        let mut listener = loop {
+            match self.connect().await {
+                Ok(listener) => break listener,
+                Err(e) => {
+                    tracing::error!(%e, retry_in = ?backoff, "Failed to listen for stream events");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_LISTEN_BACKOFF);
+                }
+            }
+        };
+        let mut last = sqlx::query_scalar!("select max(id) from stream_events")
+            .fetch_one(&self.pool)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(0);
+        let mut cleanup = tokio::time::interval(RETENTION / 6);
+        loop {
+            tokio::select! {
+                _ = cleanup.tick() => {
+                    if let Err(e) = sqlx::query!(
+                        "delete from stream_events where created_at < now() - make_interval(secs => $1)",
+                        RETENTION.as_secs_f64()
+                    )
+                    .execute(&self.pool)
+                    .await
+                    {
+                        tracing::error!(%e, "Failed to clean up stream events");
+                    }
+                },
+                // recv reconnects on its own, whatever was missed in between
+                // is picked up with the next notification
+                notification = listener.recv() => {
+                    if let Err(e) = notification {
+                        tracing::error!(%e, "Failed to receive stream event");
+                        continue;
+                    }
+                    match Published::since(last, &Filter::All, &self.pool).await {
+                        Ok(events) => {
+                            for event in events {
+                                last = event.id.unwrap_or(last);
+                                let _ = self.tx.send(event);
+                            }
+                        }
+                        Err(e) => tracing::error!(%e, "Failed to fetch stream events"),
+                    }
+                },
+            }
+        }
+    }
+
+    /// Sends viewer counts of live streams whenever they change, as long as
+    /// anyone is listening.
+    async fn poll_viewers(self, ome: Ome) {
+        let mut viewers: HashMap<String, u64> = HashMap::new();
+        let mut interval = tokio::time::interval(VIEWERS_INTERVAL);
+        loop {
+            interval.tick().await;
+            if self.tx.receiver_count() == 0 {
+                viewers.clear();
+                continue;
+            }
+            let live = sqlx::query!(
+                r#"--sql
+                select u.username, o.public and not u.hidden as "public!"
+                from stream_sessions s
+                join users u on u.id = s.user_id
+                join options o on o.user_id = s.user_id
+                where s.ended_at is null
+                "#
+            )
+            .fetch_all(&self.pool)
+            .await;
+            let live = match live {
+                Ok(live) => live,
+                Err(e) => {
+                    tracing::error!(%e, "Failed to fetch live streams");
+                    continue;
+                }
+            };
+            let stats = join_all(live.iter().map(|s| ome.stream_stats(&s.username))).await;
+            let mut current = HashMap::new();
+            for (stream, stats) in live.into_iter().zip(stats) {
+                let count = match stats {
+                    Ok(Some(stats)) => stats.viewers,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::warn!(%e, stream = stream.username, "Failed to fetch stream stats");
+                        continue;
+                    }
+                };
+                if viewers.get(&stream.username) != Some(&count) {
+                    let _ = self.tx.send(Published {
+                        id: None,
+                        stream: stream.username.clone(),
+                        public: stream.public,
+                        event: StreamEvent::Viewers { viewers: count },
+                    });
+                }
+                current.insert(stream.username, count);
+            }
+            viewers = current;
+        }
+    }
+
+    /// Events matching the filter, starting after `last_id`. Ends once the
+    /// instance drains, clients resume with another one.
+    async fn subscribe(
+        &self,
+        filter: Filter,
+        last_id: Option<i64>,
+        health: Health,
+    ) -> sqlx::Result<impl Stream<Item = Result<sse::Event, Infallible>>> {
+        // Subscribe before fetching, so nothing falls between the two
+        let rx = self.tx.subscribe();
+        let missed = match last_id {
+            Some(id) => Published::since(id, &filter, &self.pool).await?,
+            None => Vec::new(),
+        };
+        let mut seen = missed.last().and_then(|e| e.id).or(last_id).unwrap_or(0);
+        let live = BroadcastStream::new(rx).filter_map(move |event| {
+            // Lagging clients miss events, like they would with polling
+            let event = event.ok()?;
+            if !filter.matches(&event) {
+                return None;
+            }
+            if let Some(id) = event.id {
+                if id <= seen {
+                    return None;
+                }
+                seen = id;
+            }
+            Some(event)
+        });
+        let events = tokio_stream::iter(missed)
+            .chain(live)
+            .map(|event| Ok(event.to_sse()));
+        // tokio_stream has no take_until
+        Ok(futures_util::StreamExt::take_until(events, async move {
+            health.drained().await
+        }))
+    }
+}
+
+fn last_event_id(headers: &HeaderMap) -> Option<i64> {
+    headers.get("last-event-id")?.to_str().ok()?.parse().ok()
+}
+
+// ROUTES
+async fn stream_events(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(feed): Extension<Feed>,
+    Extension(health): Extension<Health>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, OvenauthError> {
+    let events = feed
+        .subscribe(Filter::Stream(stream), last_event_id(&headers), health)
+        .await?;
+    Ok(Sse::new(events).keep_alive(KeepAlive::new().interval(HEARTBEAT).text("heartbeat")))
+}
+
+async fn events(
+    headers: HeaderMap,
+    Extension(feed): Extension<Feed>,
+    Extension(health): Extension<Health>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, OvenauthError> {
+    let events = feed
+        .subscribe(Filter::Public, last_event_id(&headers), health)
+        .await?;
+    Ok(Sse::new(events).keep_alive(KeepAlive::new().interval(HEARTBEAT).text("heartbeat")))
+}
+
+pub fn routes() -> Router<PgPool> {
+    Router::new().route("/:stream/events", get(stream_events))
+}
+
+/// The feed of all streams, which can't live next to the streams themselves
+/// where any name could be a username.
+pub fn global_routes() -> Router<PgPool> {
+    Router::new().route("/streams", get(events))
+}
@@ -0,0 +1,139 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use axum_login::RequireAuthorizationLayer;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{PgPool, Result};
+
+use crate::{error::OvenauthError, session::LiveStream, user::User};
+
+/// A streamer someone follows.
+#[derive(Debug, Serialize)]
+pub struct Follow {
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Follow {
+    /// Streamers the user follows, newest first.
+    pub async fn following(user_id: i32, pool: &PgPool) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select u.username, f.created_at
+            from follows f
+            join users u on u.id = f.streamer_id
+            where f.follower_id = $1
+            order by f.created_at desc
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Following someone twice is fine.
+    pub async fn follow(user_id: i32, streamer_id: i32, pool: &PgPool) -> Result<()> {
+        sqlx::query!(
+            r#"--sql
+            insert into follows (follower_id, streamer_id)
+            values ($1, $2)
+            on conflict do nothing
+            "#,
+            user_id,
+            streamer_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn unfollow(user_id: i32, streamer_id: i32, pool: &PgPool) -> Result<()> {
+        sqlx::query!(
+            "delete from follows where follower_id = $1 and streamer_id = $2",
+            user_id,
+            streamer_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn exists(follower: &str, streamer: &str, pool: &PgPool) -> Result<bool> {
+        let row = sqlx::query!(
+            r#"--sql
+            select exists(
+                select 1 from follows f
+                join users fu on fu.id = f.follower_id
+                join users su on su.id = f.streamer_id
+                where fu.username = $1 and su.username = $2
+            ) as "exists!"
+            "#,
+            follower,
+            streamer
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(row.exists)
+    }
+}
+
+// ROUTES
+async fn following(
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let follows = Follow::following(user.id, &db).await?;
+    Ok(Json(json!({ "follows": follows })))
+}
+
+async fn live(
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let streams = LiveStream::followed(user.id, &db).await?;
+    Ok(Json(json!({ "streams": streams })))
+}
+
+async fn follow(
+    Path(stream): Path<String>,
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<Response, OvenauthError> {
+    let streamer = User::from_username(&stream, &db).await?;
+    if streamer.id == user.id {
+        return Ok((StatusCode::BAD_REQUEST, "You can't follow yourself").into_response());
+    }
+    Follow::follow(user.id, streamer.id, &db).await?;
+    Ok(Json(json!({ "following": true })).into_response())
+}
+
+async fn unfollow(
+    Path(stream): Path<String>,
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let streamer = User::from_username(&stream, &db).await?;
+    Follow::unfollow(user.id, streamer.id, &db).await?;
+    Ok(Json(json!({ "following": false })))
+}
+
+pub fn routes() -> Router<PgPool> {
+    Router::new()
+        .route("/", get(following))
+        .route("/:stream", put(follow).delete(unfollow))
+        .route_layer(RequireAuthorizationLayer::<i32, User>::login())
+}
+
+/// Kept apart from [`routes`], where any name could be a streamer's.
+pub fn following_routes() -> Router<PgPool> {
+    Router::new()
+        .route("/live", get(live))
+        .route_layer(RequireAuthorizationLayer::<i32, User>::login())
+}
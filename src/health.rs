@@ -0,0 +1,212 @@
+use std::{
+    collections::HashSet,
+    env,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgPool;
+use tokio::sync::watch;
+use tokio_util::task::TaskTracker;
+
+use crate::{ome::Ome, MIGRATOR};
+
+/// How long a single readiness check may take
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness and readiness of this instance, and the background work it has
+/// to finish before it can stop.
+#[derive(Debug, Clone)]
+pub struct Health {
+    started: Instant,
+    /// Set as soon as we are asked to stop, while we still serve traffic
+    leaving: Arc<AtomicBool>,
+    draining: Arc<watch::Sender<bool>>,
+    tasks: TaskTracker,
+    /// Whether OME being unreachable makes us unready, `READY_CHECK_OME`
+    check_ome: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn from_result<E: ToString>(res: Result<Result<(), E>, tokio::time::error::Elapsed>) -> Self {
+        let error = match res {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some("Timed out".to_string()),
+        };
+        Self {
+            ok: error.is_none(),
+            error,
+        }
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            leaving: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(watch::channel(false).0),
+            tasks: TaskTracker::new(),
+            check_ome: matches!(env::var("READY_CHECK_OME").as_deref(), Ok("1" | "true")),
+        }
+    }
+
+    /// Fails readiness from now on, so no new traffic gets sent our way.
+    pub fn leave(&self) {
+        self.leaving.store(true, Ordering::Relaxed);
+    }
+
+    pub fn leaving(&self) -> bool {
+        self.leaving.load(Ordering::Relaxed)
+    }
+
+    /// Starts shutting down: the server stops accepting connections, chat
+    /// clients are sent elsewhere and background work winds down.
+    pub fn drain(&self) {
+        self.leave();
+        self.draining.send_replace(true);
+    }
+
+    /// Resolves once the instance is draining.
+    pub async fn drained(&self) {
+        let mut rx = self.draining.subscribe();
+        // The sender lives as long as self
+        let _ = rx.wait_for(|&draining| draining).await;
+    }
+
+    /// Runs work that outlives its request, shutting down waits for it.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(task);
+    }
+
+    /// Waits for everything passed to [`Health::spawn`].
+    pub async fn finish_tasks(&self) {
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+}
+
+/// Migrations this build knows of that the database doesn't have yet.
+async fn pending_migrations(pool: &PgPool) -> sqlx::Result<Vec<i64>> {
+    let applied: HashSet<i64> =
+        sqlx::query_scalar!("select version from _sqlx_migrations where success")
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect();
+    Ok(MIGRATOR
+        .iter()
+        .map(|m| m.version)
+        .filter(|v| !applied.contains(v))
+        .collect())
+}
+
+// ROUTES
+async fn healthz(Extension(health): Extension<Health>) -> impl IntoResponse {
+    Json(json!({
+        "status": "ok",
+        "uptime": health.started.elapsed().as_secs(),
+    }))
+}
+
+async fn readyz(
+    Extension(health): Extension<Health>,
+    Extension(ome): Extension<Ome>,
+    State(pool): State<PgPool>,
+) -> Response {
+    let database = Check::from_result(
+        tokio::time::timeout(
+            CHECK_TIMEOUT,
+            sqlx::query!("select 1 as one").fetch_one(&pool),
+        )
+        .await
+        .map(|res| res.map(|_| ())),
+    );
+    let pending = tokio::time::timeout(CHECK_TIMEOUT, pending_migrations(&pool)).await;
+    let migrations = match pending {
+        Ok(Ok(pending)) if !pending.is_empty() => Check {
+            ok: false,
+            error: Some(format!("Pending migrations: {pending:?}")),
+        },
+        res => Check::from_result(res.map(|res| res.map(|_| ()))),
+    };
+    let ome = if health.check_ome {
+        Some(Check::from_result(
+            tokio::time::timeout(CHECK_TIMEOUT, ome.reachable()).await,
+        ))
+    } else {
+        None
+    };
+    let draining = health.leaving();
+    let ready = !draining && database.ok && migrations.ok && ome.as_ref().is_none_or(|c| c.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = Json(json!({
+        "ready": ready,
+        "draining": draining,
+        "checks": {
+            "database": database,
+            "migrations": migrations,
+            "ome": ome,
+        },
+    }));
+    (status, body).into_response()
+}
+
+pub fn routes() -> Router<PgPool> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+/// Resolves on SIGTERM or Ctrl+C. Readiness fails right away, but we keep
+/// serving for `pre_stop` so load balancers notice before we stop accepting
+/// connections.
+pub async fn shutdown_signal(health: Health, pre_stop: Duration) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Ctrl+C handler to be installed");
+    };
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler to be installed")
+            .recv()
+            .await;
+    };
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    health.leave();
+    tracing::info!(?pre_stop, "Shutting down");
+    tokio::time::sleep(pre_stop).await;
+    health.drain();
+}
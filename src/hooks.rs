@@ -0,0 +1,601 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use axum_login::RequireAuthorizationLayer;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use ulid::Ulid;
+use url::Url;
+
+use crate::{
+    chat::OutgoingMessage, error::OvenauthError, health::Health, outbound::Outbound, user::User,
+};
+
+pub const EVENTS: [&str; 5] = [
+    "user.registered",
+    "stream.started",
+    "stream.ended",
+    "chat.message",
+    "options.updated",
+];
+const MAX_HOOKS: i64 = 10;
+const MAX_ATTEMPTS: i32 = 8;
+/// Doubled after every failed attempt
+const BACKOFF_SECS: i32 = 10;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const BATCH_SIZE: i64 = 20;
+/// Delivered events are kept around this long
+const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long we go without checking which events hooks want. Hooks created
+/// on other instances miss events for up to this long.
+const SUBSCRIBED_TTL: Duration = Duration::from_secs(5);
+
+static SUBSCRIBED: Subscribed = Subscribed::new();
+
+/// Something integrators can subscribe to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Event {
+    #[serde(rename = "user.registered")]
+    UserRegistered { username: String },
+    #[serde(rename = "stream.started")]
+    StreamStarted {
+        username: String,
+        protocol: String,
+        started_at: DateTime<Utc>,
+    },
+    #[serde(rename = "stream.ended")]
+    StreamEnded {
+        username: String,
+        protocol: String,
+        ended_at: DateTime<Utc>,
+    },
+    #[serde(rename = "chat.message")]
+    ChatMessage {
+        room: String,
+        message: Box<OutgoingMessage>,
+    },
+    /// Everything but the stream key
+    #[serde(rename = "options.updated")]
+    OptionsUpdated { username: String, options: Value },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::UserRegistered { .. } => EVENTS[0],
+            Self::StreamStarted { .. } => EVENTS[1],
+            Self::StreamEnded { .. } => EVENTS[2],
+            Self::ChatMessage { .. } => EVENTS[3],
+            Self::OptionsUpdated { .. } => EVENTS[4],
+        }
+    }
+}
+
+/// Events any hook wants.
+#[derive(Debug)]
+struct Wanted {
+    all: bool,
+    events: HashSet<String>,
+}
+
+/// Caches which events anybody has hooks for, so the frequent ones like
+/// `chat.message` don't cost a query while nobody listens.
+#[derive(Debug)]
+struct Subscribed(RwLock<Option<(Instant, Wanted)>>);
+
+impl Subscribed {
+    const fn new() -> Self {
+        Self(RwLock::new(None))
+    }
+
+    /// Errs on the side of queueing when the hooks can't be checked.
+    async fn wants(&self, event: &str, pool: &PgPool) -> bool {
+        if let Some((checked, wanted)) = &*self.0.read().unwrap() {
+            if checked.elapsed() < SUBSCRIBED_TTL {
+                return wanted.all || wanted.events.contains(event);
+            }
+        }
+        let res = sqlx::query!(
+            r#"--sql
+            select coalesce(bool_or(events = '{}'), false) as "all!",
+                coalesce(array_agg(distinct e) filter (where e is not null), '{}') as "events!"
+            from event_hooks
+            left join lateral unnest(events) e on true
+            "#
+        )
+        .fetch_one(pool)
+        .await;
+        match res {
+            Ok(row) => {
+                let wanted = Wanted {
+                    all: row.all,
+                    events: row.events.into_iter().collect(),
+                };
+                let wants = wanted.all || wanted.events.contains(event);
+                *self.0.write().unwrap() = Some((Instant::now(), wanted));
+                wants
+            }
+            Err(e) => {
+                tracing::error!(%e, "Failed to check for hooks");
+                true
+            }
+        }
+    }
+
+    /// Hooks changed, check again next time.
+    fn forget(&self) {
+        *self.0.write().unwrap() = None;
+    }
+}
+
+/// Queues the event for the user's hooks and the global ones. Once stored
+/// it gets delivered at least once, so this is awaited rather than spawned
+/// to not lose events to a shutdown.
+pub async fn emit(pool: &PgPool, username: &str, event: Event) {
+    let name = event.name();
+    if !SUBSCRIBED.wants(name, pool).await {
+        return;
+    }
+    let id = Ulid::new().to_string();
+    let mut payload = json!(event);
+    payload["id"] = json!(id);
+    payload["created_at"] = json!(Utc::now());
+    let res = sqlx::query!(
+        r#"--sql
+        insert into event_deliveries (hook_id, event_id, event, payload)
+        select id, $3, $2, $4
+        from event_hooks
+        where (user_id is null or user_id = (select id from users where username = $1))
+            and (events = '{}' or $2 = any(events))
+        "#,
+        username,
+        name,
+        id,
+        payload
+    )
+    .execute(pool)
+    .await;
+    if let Err(e) = res {
+        tracing::error!(%e, event = name, "Failed to queue event");
+    }
+}
+
+/// Whose hooks a router manages.
+#[derive(Debug, Clone, Copy)]
+pub enum Scope {
+    User,
+    Global,
+}
+
+impl Scope {
+    fn owner(self, user: &User) -> Option<i32> {
+        match self {
+            Self::User => Some(user.id),
+            Self::Global => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Hook {
+    pub id: i64,
+    pub url: String,
+    /// Key of the `X-Ovenauth-Signature` HMAC
+    pub secret: String,
+    pub events: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewHook {
+    url: String,
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Delivery {
+    pub id: i64,
+    pub event_id: String,
+    pub event: String,
+    pub status: String,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A delivery taken by this instance.
+#[derive(Debug)]
+struct Due {
+    id: i64,
+    event: String,
+    payload: Value,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+impl Hook {
+    async fn all(owner: Option<i32>, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select id, url, secret, events, created_at
+            from event_hooks
+            where user_id is not distinct from $1
+            order by id
+            "#,
+            owner
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// `None` if the owner already has [`MAX_HOOKS`].
+    async fn create(
+        owner: Option<i32>,
+        hook: &NewHook,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        let secret = URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; 32]>());
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            insert into event_hooks (user_id, url, secret, events)
+            select $1, $2, $3, $4
+            where (select count(*) from event_hooks where user_id is not distinct from $1) < $5
+            returning id, url, secret, events, created_at
+            "#,
+            owner,
+            hook.url,
+            secret,
+            &hook.events,
+            MAX_HOOKS
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    async fn delete(owner: Option<i32>, id: i64, pool: &PgPool) -> sqlx::Result<bool> {
+        let res = sqlx::query!(
+            "delete from event_hooks where id = $1 and user_id is not distinct from $2",
+            id,
+            owner
+        )
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn exists(owner: Option<i32>, id: i64, pool: &PgPool) -> sqlx::Result<bool> {
+        let row = sqlx::query!(
+            r#"select exists(select 1 from event_hooks where id = $1 and user_id is not distinct from $2) as "exists!""#,
+            id,
+            owner
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(row.exists)
+    }
+}
+
+impl Delivery {
+    async fn recent(hook_id: i64, status: Option<&str>, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select id, event_id, event, status, attempts, response_status, error, created_at, updated_at
+            from event_deliveries
+            where hook_id = $1 and ($2::text is null or status = $2)
+            order by id desc
+            limit 100
+            "#,
+            hook_id,
+            status
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Queues failed deliveries of the hook again, all of them or just one.
+    async fn replay(hook_id: i64, id: Option<i64>, pool: &PgPool) -> sqlx::Result<u64> {
+        let res = sqlx::query!(
+            r#"--sql
+            update event_deliveries
+            set status = 'pending', attempts = 0, next_attempt_at = now(), updated_at = now()
+            where hook_id = $1 and status = 'failed' and ($2::bigint is null or id = $2)
+            "#,
+            hook_id,
+            id
+        )
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    /// Takes due deliveries. They are leased for a minute, if this instance
+    /// dies in between another one sends them again.
+    async fn due(pool: &PgPool) -> sqlx::Result<Vec<Due>> {
+        sqlx::query_as!(
+            Due,
+            r#"--sql
+            update event_deliveries d
+            set next_attempt_at = now() + interval '1 minute'
+            from event_hooks h
+            where h.id = d.hook_id and d.id in (
+                select id from event_deliveries
+                where status = 'pending' and next_attempt_at <= now()
+                order by next_attempt_at
+                limit $1
+                for update skip locked
+            )
+            returning d.id as "id!", d.event as "event!", d.payload as "payload!",
+                d.attempts as "attempts!", h.url, h.secret
+            "#,
+            BATCH_SIZE
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn attempted(
+        id: i64,
+        delivered: bool,
+        response_status: Option<i32>,
+        error: Option<&str>,
+        pool: &PgPool,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"--sql
+            update event_deliveries
+            set attempts = attempts + 1,
+                status = case
+                    when $2 then 'delivered'
+                    when attempts + 1 >= $5 then 'failed'
+                    else 'pending'
+                    end,
+                next_attempt_at = now() + make_interval(secs => $6 * 2 ^ attempts),
+                response_status = $3,
+                error = $4,
+                updated_at = now()
+            where id = $1
+            "#,
+            id,
+            delivered,
+            response_status,
+            error,
+            MAX_ATTEMPTS,
+            f64::from(BACKOFF_SECS)
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Signs like OME signs its admission webhooks: HMAC-SHA1, base64url.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+    URL_SAFE_NO_PAD.encode(hmac::sign(&key, body))
+}
+
+async fn send(outbound: Outbound, client: reqwest::Client, due: Due, pool: PgPool) {
+    if !Url::parse(&due.url).is_ok_and(|url| outbound.permitted(&url)) {
+        let error = "Refused to send to a private address";
+        if let Err(e) = Delivery::attempted(due.id, false, None, Some(error), &pool).await {
+            tracing::error!(%e, id = due.id, "Failed to log event delivery");
+        }
+        return;
+    }
+    let body = due.payload.to_string();
+    let res = client
+        .post(&due.url)
+        .header("Content-Type", "application/json")
+        .header("X-Ovenauth-Event", &due.event)
+        .header("X-Ovenauth-Delivery", due.id)
+        .header("X-Ovenauth-Signature", sign(&due.secret, body.as_bytes()))
+        .body(body)
+        .send()
+        .await;
+    let (delivered, response_status, error) = match res {
+        Ok(res) if res.status().is_success() => (true, Some(res.status()), None),
+        Ok(res) => (
+            false,
+            Some(res.status()),
+            Some(format!("Responded with {}", res.status())),
+        ),
+        Err(e) => (false, None, Some(e.to_string())),
+    };
+    let response_status = response_status.map(|s| i32::from(s.as_u16()));
+    if let Err(e) =
+        Delivery::attempted(due.id, delivered, response_status, error.as_deref(), &pool).await
+    {
+        tracing::error!(%e, id = due.id, "Failed to log event delivery");
+    }
+    if !delivered && due.attempts + 1 >= MAX_ATTEMPTS {
+        tracing::warn!(
+            id = due.id,
+            url = due.url,
+            error,
+            "Giving up on event delivery"
+        );
+    }
+}
+
+/// Sends queued events until the instance drains. Events already taken are
+/// finished first.
+pub async fn deliver(pool: PgPool, health: Health, outbound: Outbound) {
+    let client = outbound.client(Duration::from_secs(10));
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = health.drained() => return,
+            _ = poll.tick() => {
+                match Delivery::due(&pool).await {
+                    Ok(due) => {
+                        for due in due {
+                            health.spawn(send(outbound.clone(), client.clone(), due, pool.clone()));
+                        }
+                    }
+                    Err(e) => tracing::error!(%e, "Failed to fetch due events"),
+                }
+            }
+            _ = cleanup.tick() => {
+                let res = sqlx::query!(
+                    "delete from event_deliveries where status = 'delivered' and updated_at < now() - make_interval(secs => $1)",
+                    RETENTION.as_secs_f64()
+                )
+                .execute(&pool)
+                .await;
+                if let Err(e) = res {
+                    tracing::error!(%e, "Failed to clean up event deliveries");
+                }
+            }
+        }
+    }
+}
+
+// ROUTES
+async fn hooks(
+    Extension(scope): Extension<Scope>,
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let hooks = Hook::all(scope.owner(&user), &db).await?;
+    Ok(Json(json!({ "hooks": hooks, "events": EVENTS })))
+}
+
+async fn create_hook(
+    Extension(scope): Extension<Scope>,
+    Extension(user): Extension<User>,
+    Extension(outbound): Extension<Outbound>,
+    State(db): State<PgPool>,
+    Json(hook): Json<NewHook>,
+) -> Result<Response, OvenauthError> {
+    if !Url::parse(&hook.url).is_ok_and(|url| outbound.permitted(&url)) {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid URL").into_response());
+    }
+    if let Some(unknown) = hook.events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+        return Ok((StatusCode::BAD_REQUEST, format!("Unknown event {unknown}")).into_response());
+    }
+    match Hook::create(scope.owner(&user), &hook, &db).await? {
+        Some(hook) => {
+            SUBSCRIBED.forget();
+            Ok(Json(json!({ "hook": hook })).into_response())
+        }
+        None => Ok((StatusCode::CONFLICT, "Too many hooks").into_response()),
+    }
+}
+
+async fn delete_hook(
+    Path(id): Path<i64>,
+    Extension(scope): Extension<Scope>,
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<Response, OvenauthError> {
+    if Hook::delete(scope.owner(&user), id, &db).await? {
+        SUBSCRIBED.forget();
+        Ok(Json(json!({ "deleted": id })).into_response())
+    } else {
+        Ok((StatusCode::NOT_FOUND, "Not Found").into_response())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeliveryQuery {
+    status: Option<String>,
+}
+
+async fn deliveries(
+    Path(id): Path<i64>,
+    Query(query): Query<DeliveryQuery>,
+    Extension(scope): Extension<Scope>,
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<Response, OvenauthError> {
+    if !Hook::exists(scope.owner(&user), id, &db).await? {
+        return Ok((StatusCode::NOT_FOUND, "Not Found").into_response());
+    }
+    let deliveries = Delivery::recent(id, query.status.as_deref(), &db).await?;
+    Ok(Json(json!({ "deliveries": deliveries })).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayQuery {
+    delivery: Option<i64>,
+}
+
+async fn replay(
+    Path(id): Path<i64>,
+    Query(query): Query<ReplayQuery>,
+    Extension(scope): Extension<Scope>,
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<Response, OvenauthError> {
+    if !Hook::exists(scope.owner(&user), id, &db).await? {
+        return Ok((StatusCode::NOT_FOUND, "Not Found").into_response());
+    }
+    let replayed = Delivery::replay(id, query.delivery, &db).await?;
+    Ok(Json(json!({ "replayed": replayed })).into_response())
+}
+
+/// [`Scope::Global`] hooks must only be nested behind an admin check.
+pub fn routes(scope: Scope) -> Router<PgPool> {
+    Router::new()
+        .route("/", get(hooks).post(create_hook))
+        .route("/:id", delete(delete_hook))
+        .route("/:id/deliveries", get(deliveries))
+        .route("/:id/replay", post(replay))
+        .route_layer(RequireAuthorizationLayer::<i32, User>::login())
+        .layer(Extension(scope))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(events: &[&str]) -> NewHook {
+        NewHook {
+            url: "https://example.com/hook".to_string(),
+            events: events.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn checks_for_hooks_once_in_a_while(pool: PgPool) {
+        let subscribed = Subscribed::new();
+        assert!(!subscribed.wants("chat.message", &pool).await);
+
+        Hook::create(None, &hook(&["chat.message"]), &pool)
+            .await
+            .unwrap();
+        // Still cached
+        assert!(!subscribed.wants("chat.message", &pool).await);
+        subscribed.forget();
+        assert!(subscribed.wants("chat.message", &pool).await);
+        assert!(!subscribed.wants("stream.started", &pool).await);
+
+        // No events means all of them
+        Hook::create(None, &hook(&[]), &pool).await.unwrap();
+        subscribed.forget();
+        assert!(subscribed.wants("stream.started", &pool).await);
+    }
+}
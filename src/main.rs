@@ -1,57 +1,65 @@
-use axum::Router;
+use axum::{middleware, Extension, Router};
 use axum_login::{
     axum_sessions::{async_session::MemoryStore, SessionLayer},
     AuthLayer, PostgresStore,
 };
 use dotenvy::dotenv;
 use rand::Rng;
-use sqlx::PgPool;
-use std::{env, net::IpAddr};
+use sqlx::{migrate::Migrator, PgPool};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 use tower_http::{
     cors::CorsLayer,
-    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
 };
 use tracing::Level;
-use tracing_subscriber::prelude::*;
 use user::User;
 
+mod admin;
+mod audit;
 mod chat;
+mod emotes;
 mod error;
+mod feed;
+mod follow;
+mod health;
+mod hooks;
+mod notifier;
+mod ome;
 mod options;
+mod outbound;
+mod push;
+mod recording;
+mod session;
 mod stream;
+mod telemetry;
 mod user;
 mod webhook;
-mod notifier;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// How long requests, chat sockets and background tasks get to finish once
+/// we are asked to stop
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long we keep serving after failing readiness
+const DEFAULT_SHUTDOWN_DELAY: Duration = Duration::from_secs(5);
 
 async fn connect_to_db(db_url: &str) -> sqlx::Result<PgPool> {
     let db_pool = PgPool::connect(db_url).await?;
-    sqlx::migrate!("./migrations").run(&db_pool).await?;
+    MIGRATOR.run(&db_pool).await?;
     Ok(db_pool)
 }
 
-fn setup_tracing() {
-    let console_layer = console_subscriber::spawn();
-    tracing_subscriber::registry()
-        .with(console_layer)
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                "ovenauth=debug,tower_http=info,axum::rejection=trace,tokio=trace,runtime=trace"
-                    .into()
-            }),
-        )
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_target(false)
-                .compact(),
-        )
-        .init();
-}
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
 
-    setup_tracing();
+    telemetry::setup_tracing()?;
+    let metrics = telemetry::install()?;
 
     let db_url = env::var("DATABASE_URL").expect("DATABASE_URL is not set");
     let host = env::var("LISTEN").expect("LISTEN is not set");
@@ -72,26 +80,84 @@ async fn main() -> anyhow::Result<()> {
     let session_layer = SessionLayer::new(session_store, &secret).with_secure(false);
     let auth_layer = AuthLayer::new(user_store, &secret);
     let cors = CorsLayer::very_permissive();
+    let health = health::Health::new();
+    let chat = chat::Chat::new(db_pool.clone(), health.clone());
+    let ome = ome::Ome::from_env();
+    let outbound = outbound::Outbound::from_env();
+    let push = push::Push::new(db_pool.clone(), outbound.clone()).await?;
+    let notifier = notifier::Notifier::new(db_pool.clone(), push.clone(), outbound.clone());
+    let feed = feed::Feed::new(db_pool.clone(), ome.clone());
+    health.spawn(notifier.clone().deliver(health.clone()));
+    health.spawn(hooks::deliver(
+        db_pool.clone(),
+        health.clone(),
+        outbound.clone(),
+    ));
+    let shutdown_timeout = env::var("SHUTDOWN_TIMEOUT")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+    let shutdown_delay = env::var("SHUTDOWN_DELAY")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SHUTDOWN_DELAY);
 
     tracing::info!("Starting server on {}:{}", host, port);
     let app: Router = Router::new()
         .merge(webhook::routes())
         .nest("/user", user::routes())
+        .nest("/admin", admin::routes())
         .nest("/stream", stream::routes())
-        .nest("/chat", chat::routes())
+        .nest("/events", feed::global_routes())
+        .nest("/chat", chat.routes())
+        .merge(telemetry::routes(metrics))
+        .merge(health::routes())
+        .layer(middleware::from_fn(telemetry::track_requests))
+        .layer(Extension(chat.clone()))
+        .layer(Extension(ome))
+        .layer(Extension(notifier))
+        .layer(Extension(push))
+        .layer(Extension(outbound))
+        .layer(Extension(feed))
+        .layer(Extension(health.clone()))
         .layer(auth_layer)
         .layer(session_layer)
         .layer(cors)
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .make_span_with(telemetry::request_span)
                 .on_response(DefaultOnResponse::new().level(Level::INFO)),
         )
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .with_state(db_pool);
 
-    axum::Server::bind(&(host.parse::<IpAddr>()?, port.parse()?).into())
-        .serve(app.into_make_service())
-        .await?;
+    let server = axum::Server::bind(&(host.parse::<IpAddr>()?, port.parse()?).into())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(health::shutdown_signal(health.clone(), shutdown_delay));
+    // The server only waits for plain requests, upgraded chat sockets and
+    // spawned tasks are on us
+    let shutdown = async {
+        let chat = async {
+            health.drained().await;
+            chat.shutdown().await;
+            Ok(())
+        };
+        tokio::try_join!(server, chat)?;
+        health.finish_tasks().await;
+        anyhow::Ok(())
+    };
+    let deadline = async {
+        health.drained().await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    tokio::select! {
+        res = shutdown => res?,
+        _ = deadline => tracing::warn!("Shutdown timed out, dropping what is left"),
+    }
+    telemetry::shutdown_tracing();
 
     Ok(())
 }
@@ -0,0 +1,745 @@
+use std::{env, time::Duration};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use axum_login::RequireAuthorizationLayer;
+use chrono::{DateTime, Utc};
+use reqwest::header::RETRY_AFTER;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use url::Url;
+
+use crate::{
+    error::OvenauthError, health::Health, options::PublicOptions, outbound::Outbound, push::Push,
+    user::User,
+};
+
+const MAX_ATTEMPTS: i32 = 5;
+/// Doubled after every failed attempt
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_SINKS: i64 = 10;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const BATCH_SIZE: i64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum SinkKind {
+    /// Gets the event as JSON
+    Webhook,
+    /// Gets a Discord webhook message
+    Discord,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// Somewhere a user wants to be told about their stream.
+#[derive(Debug, Serialize)]
+pub struct Sink {
+    pub id: i64,
+    pub kind: SinkKind,
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewSink {
+    kind: SinkKind,
+    url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Delivery {
+    pub id: i64,
+    pub sink_id: i64,
+    pub event: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A delivery taken by this instance.
+#[derive(Debug)]
+struct Due {
+    id: i64,
+    payload: Value,
+    attempts: i32,
+    url: String,
+}
+
+/// What came of sending a delivery once.
+#[derive(Debug)]
+struct Attempt {
+    status: DeliveryStatus,
+    response_status: Option<i32>,
+    error: Option<String>,
+    /// Until the next attempt, if there is one
+    retry_in: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Live {
+        username: String,
+        title: Option<String>,
+        url: Option<String>,
+        started_at: DateTime<Utc>,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Live { .. } => "live",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::Live {
+                username,
+                title: Some(title),
+                ..
+            } => format!("{username} went live with title {title}"),
+            Self::Live { username, .. } => format!("{username} went live"),
+        }
+    }
+
+    /// What the service worker shows. Push messages are small, so long titles
+    /// get cut.
+    fn web_push(&self) -> Value {
+        let Self::Live { username, url, .. } = self;
+        let message: String = self.message().chars().take(1000).collect();
+        json!({ "title": message, "url": url, "tag": format!("live-{username}") })
+    }
+
+    fn payload(&self, kind: SinkKind) -> Value {
+        match kind {
+            SinkKind::Webhook => json!(self),
+            SinkKind::Discord => {
+                let mut content = self.message();
+                let Self::Live { url, .. } = self;
+                if let Some(url) = url {
+                    content = format!("{content}\n{url}");
+                }
+                // Titles are user input, so no pinging @everyone
+                json!({ "content": content, "allowed_mentions": { "parse": [] } })
+            }
+        }
+    }
+}
+
+impl Sink {
+    pub async fn all(user_id: i32, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select id, kind as "kind: _", url, created_at
+            from notification_sinks
+            where user_id = $1
+            order by id
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// `None` if the user already has [`MAX_SINKS`].
+    async fn create(user_id: i32, sink: &NewSink, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            insert into notification_sinks (user_id, kind, url)
+            select $1, $2, $3
+            where (select count(*) from notification_sinks where user_id = $1) < $4
+            returning id, kind as "kind: _", url, created_at
+            "#,
+            user_id,
+            sink.kind as _,
+            sink.url,
+            MAX_SINKS
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    async fn delete(user_id: i32, id: i64, pool: &PgPool) -> sqlx::Result<bool> {
+        let res = sqlx::query!(
+            "delete from notification_sinks where id = $1 and user_id = $2",
+            id,
+            user_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
+
+impl Delivery {
+    async fn create(
+        sink_id: i64,
+        event: &str,
+        payload: &Value,
+        pool: &PgPool,
+    ) -> sqlx::Result<i64> {
+        let row = sqlx::query!(
+            r#"--sql
+            insert into notification_deliveries (sink_id, event, payload)
+            values ($1, $2, $3)
+            returning id
+            "#,
+            sink_id,
+            event,
+            payload
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(row.id)
+    }
+
+    /// Takes due deliveries. They are leased for a minute, if this instance
+    /// dies in between another one sends them again.
+    async fn due(pool: &PgPool) -> sqlx::Result<Vec<Due>> {
+        sqlx::query_as!(
+            Due,
+            r#"--sql
+            update notification_deliveries d
+            set next_attempt_at = now() + interval '1 minute'
+            from notification_sinks s
+            where s.id = d.sink_id and d.id in (
+                select id from notification_deliveries
+                where status = 'pending' and next_attempt_at <= now()
+                order by next_attempt_at
+                limit $1
+                for update skip locked
+            )
+            returning d.id as "id!", d.payload as "payload!", d.attempts as "attempts!", s.url
+            "#,
+            BATCH_SIZE
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn attempted(id: i64, attempt: &Attempt, pool: &PgPool) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"--sql
+            update notification_deliveries
+            set attempts = attempts + 1,
+                status = $2,
+                response_status = $3,
+                error = $4,
+                next_attempt_at = now() + make_interval(secs => $5),
+                updated_at = now()
+            where id = $1
+            "#,
+            id,
+            attempt.status as _,
+            attempt.response_status,
+            attempt.error,
+            attempt.retry_in.as_secs_f64()
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The user's latest deliveries.
+    pub async fn recent(user_id: i32, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select d.id, d.sink_id, d.event, d.status as "status: _", d.attempts,
+                d.response_status, d.error, d.created_at, d.updated_at
+            from notification_deliveries d
+            join notification_sinks s on s.id = d.sink_id
+            where s.user_id = $1
+            order by d.id desc
+            limit 50
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
+
+/// An event in a user's inbox.
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    pub id: i64,
+    pub event: String,
+    pub payload: Value,
+    pub read_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Notification {
+    /// Puts the event into the inbox of everyone following the streamer.
+    pub async fn fan_out(streamer_id: i32, event: &Event, pool: &PgPool) -> sqlx::Result<u64> {
+        let res = sqlx::query!(
+            r#"--sql
+            insert into notifications (user_id, event, payload)
+            select follower_id, $2, $3
+            from follows
+            where streamer_id = $1
+            "#,
+            streamer_id,
+            event.name(),
+            json!(event)
+        )
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    /// The user's latest notifications.
+    pub async fn recent(user_id: i32, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select id, event, payload, read_at, created_at
+            from notifications
+            where user_id = $1
+            order by id desc
+            limit 50
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn unread(user_id: i32, pool: &PgPool) -> sqlx::Result<i64> {
+        let row = sqlx::query!(
+            r#"select count(*) as "count!" from notifications where user_id = $1 and read_at is null"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(row.count)
+    }
+
+    pub async fn read_all(user_id: i32, pool: &PgPool) -> sqlx::Result<()> {
+        sqlx::query!(
+            "update notifications set read_at = now() where user_id = $1 and read_at is null",
+            user_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Sends events to the sinks users set up.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    outbound: Outbound,
+    pool: PgPool,
+    /// Where the frontend lives, to link to streams
+    public_url: Option<String>,
+    push: Push,
+}
+
+impl Notifier {
+    /// Links to streams if `PUBLIC_URL` is set.
+    pub fn new(pool: PgPool, push: Push, outbound: Outbound) -> Self {
+        let client = outbound.client(Duration::from_secs(10));
+        Self {
+            client,
+            outbound,
+            pool,
+            public_url: env::var("PUBLIC_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_string()),
+            push,
+        }
+    }
+
+    /// Tells everyone the user went live, unless they only reconnected.
+    pub async fn live(&self, user: &User) -> Result<()> {
+        let recent = sqlx::query!(
+            r#"--sql
+            select coalesce(max(ended_at) > now() - interval '5 minutes', false) as "recent!"
+            from stream_sessions where user_id = $1
+            "#,
+            user.id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if recent.recent {
+            tracing::debug!(user = user.username, "Reconnected, not notifying");
+            return Ok(());
+        }
+        let options = PublicOptions::from_username(&user.username, &self.pool).await?;
+        let event = Event::Live {
+            username: user.username.clone(),
+            title: options.name,
+            url: self
+                .public_url
+                .as_ref()
+                .map(|base| format!("{base}/{}", user.username)),
+            started_at: Utc::now(),
+        };
+        let followers = Notification::fan_out(user.id, &event, &self.pool).await?;
+        tracing::debug!(user = user.username, followers, "Notified followers");
+        if let Err(e) = self.push.notify_followers(user.id, &event.web_push()).await {
+            tracing::error!(%e, user = user.username, "Failed to push to followers");
+        }
+        self.send(user.id, &event).await
+    }
+
+    /// Queues a delivery to each of the user's sinks, [`Notifier::deliver`]
+    /// sends them.
+    pub async fn send(&self, user_id: i32, event: &Event) -> Result<()> {
+        for sink in Sink::all(user_id, &self.pool).await? {
+            let payload = event.payload(sink.kind);
+            Delivery::create(sink.id, event.name(), &payload, &self.pool).await?;
+        }
+        Ok(())
+    }
+
+    /// Posts the delivery once. Server errors, rate limits and network errors
+    /// are retried with backoff, or after `Retry-After` if the sink says so.
+    async fn attempt(&self, due: &Due) -> Attempt {
+        if !Url::parse(&due.url).is_ok_and(|url| self.outbound.permitted(&url)) {
+            return Attempt {
+                status: DeliveryStatus::Failed,
+                response_status: None,
+                error: Some("Refused to send to a private address".to_string()),
+                retry_in: Duration::ZERO,
+            };
+        }
+        let (retry, response_status, error, retry_after) =
+            match self.client.post(&due.url).json(&due.payload).send().await {
+                Ok(res) if res.status().is_success() => {
+                    return Attempt {
+                        status: DeliveryStatus::Delivered,
+                        response_status: Some(i32::from(res.status().as_u16())),
+                        error: None,
+                        retry_in: Duration::ZERO,
+                    };
+                }
+                Ok(res) => {
+                    let status = res.status();
+                    let retry_after = res
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse().ok())
+                        .map(Duration::from_secs);
+                    (
+                        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                        Some(i32::from(status.as_u16())),
+                        format!("Responded with {status}"),
+                        retry_after,
+                    )
+                }
+                // Can't connect or timed out
+                Err(e) => (true, None, e.to_string(), None),
+            };
+        let status = if retry && due.attempts + 1 < MAX_ATTEMPTS {
+            DeliveryStatus::Pending
+        } else {
+            DeliveryStatus::Failed
+        };
+        let backoff = BACKOFF * 2u32.pow(due.attempts.clamp(0, 16) as u32);
+        Attempt {
+            status,
+            response_status,
+            error: Some(error),
+            retry_in: retry_after.unwrap_or(backoff).min(MAX_BACKOFF),
+        }
+    }
+
+    async fn send_due(self, due: Due) {
+        let attempt = self.attempt(&due).await;
+        if let Err(e) = Delivery::attempted(due.id, &attempt, &self.pool).await {
+            tracing::error!(%e, id = due.id, "Failed to log delivery");
+        }
+        if attempt.status == DeliveryStatus::Failed {
+            tracing::warn!(
+                id = due.id,
+                url = due.url,
+                error = attempt.error,
+                "Giving up on notification"
+            );
+        }
+    }
+
+    /// Sends queued deliveries until the instance drains. Deliveries already
+    /// taken are finished first.
+    pub async fn deliver(self, health: Health) {
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = poll.tick() => {}
+                _ = health.drained() => return,
+            }
+            match Delivery::due(&self.pool).await {
+                Ok(due) => {
+                    for due in due {
+                        health.spawn(self.clone().send_due(due));
+                    }
+                }
+                Err(e) => tracing::error!(%e, "Failed to fetch due notifications"),
+            }
+        }
+    }
+}
+
+// ROUTES
+async fn sinks(
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let sinks = Sink::all(user.id, &db).await?;
+    Ok(Json(json!({ "sinks": sinks })))
+}
+
+async fn create_sink(
+    Extension(user): Extension<User>,
+    Extension(outbound): Extension<Outbound>,
+    State(db): State<PgPool>,
+    Json(sink): Json<NewSink>,
+) -> Result<Response, OvenauthError> {
+    if !Url::parse(&sink.url).is_ok_and(|url| outbound.permitted(&url)) {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid URL").into_response());
+    }
+    match Sink::create(user.id, &sink, &db).await? {
+        Some(sink) => Ok(Json(json!({ "sink": sink })).into_response()),
+        None => Ok((StatusCode::CONFLICT, "Too many notification sinks").into_response()),
+    }
+}
+
+async fn delete_sink(
+    Path(id): Path<i64>,
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<Response, OvenauthError> {
+    if Sink::delete(user.id, id, &db).await? {
+        Ok(Json(json!({ "deleted": id })).into_response())
+    } else {
+        Ok((StatusCode::NOT_FOUND, "Not Found").into_response())
+    }
+}
+
+async fn deliveries(
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let deliveries = Delivery::recent(user.id, &db).await?;
+    Ok(Json(json!({ "deliveries": deliveries })))
+}
+
+async fn inbox(
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let notifications = Notification::recent(user.id, &db).await?;
+    let unread = Notification::unread(user.id, &db).await?;
+    Ok(Json(
+        json!({ "notifications": notifications, "unread": unread }),
+    ))
+}
+
+async fn read_inbox(
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    Notification::read_all(user.id, &db).await?;
+    Ok(Json(json!({ "unread": 0 })))
+}
+
+pub fn routes() -> Router<PgPool> {
+    Router::new()
+        .route("/inbox", get(inbox))
+        .route("/inbox/read", post(read_inbox))
+        .route("/sinks", get(sinks).post(create_sink))
+        .route("/sinks/:id", delete(delete_sink))
+        .route("/deliveries", get(deliveries))
+        .route_layer(RequireAuthorizationLayer::<i32, User>::login())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        http::{header::RETRY_AFTER, Uri},
+        response::IntoResponse,
+    };
+
+    use super::*;
+
+    /// Stand-in for the sinks users set up, answers depending on the path.
+    async fn sink(uri: Uri) -> Response {
+        match uri.path() {
+            "/ok" => StatusCode::NO_CONTENT.into_response(),
+            "/down" => StatusCode::BAD_GATEWAY.into_response(),
+            "/busy" => (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, "30")]).into_response(),
+            _ => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    async fn serve() -> SocketAddr {
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 2], 0)))
+            .serve(Router::new().fallback(sink).into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Queues a delivery to `url` for a new user.
+    async fn queue(url: String, pool: &PgPool) -> i32 {
+        let user_id: i32 = sqlx::query_scalar(
+            "insert into users (username, password) values ('alice', '') returning id",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        let sink = NewSink {
+            kind: SinkKind::Webhook,
+            url,
+        };
+        let sink = Sink::create(user_id, &sink, pool).await.unwrap().unwrap();
+        Delivery::create(sink.id, "live", &json!({ "event": "live" }), pool)
+            .await
+            .unwrap();
+        user_id
+    }
+
+    /// Sends what is due, like one tick of [`Notifier::deliver`].
+    async fn tick(notifier: &Notifier) {
+        for due in Delivery::due(&notifier.pool).await.unwrap() {
+            notifier.clone().send_due(due).await;
+        }
+    }
+
+    /// The user's logged delivery, and the seconds until it is due again.
+    async fn logged(user_id: i32, pool: &PgPool) -> (Delivery, f64) {
+        let mut deliveries = Delivery::recent(user_id, pool).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        let delivery = deliveries.remove(0);
+        let next_in = sqlx::query_scalar(
+            "select extract(epoch from next_attempt_at - now())::float8 from notification_deliveries where id = $1",
+        )
+        .bind(delivery.id)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        (delivery, next_in)
+    }
+
+    async fn notifier(pool: PgPool) -> Notifier {
+        // Sinks are served on 127.0.0.2
+        let outbound = Outbound::allowing(["127.0.0.2"]);
+        let push = Push::new(pool.clone(), outbound.clone()).await.unwrap();
+        Notifier::new(pool, push, outbound)
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn delivers(pool: PgPool) {
+        let addr = serve().await;
+        let notifier = notifier(pool.clone()).await;
+        let user_id = queue(format!("http://{addr}/ok"), &pool).await;
+        tick(&notifier).await;
+        let (delivery, _) = logged(user_id, &pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.response_status, Some(204));
+        assert_eq!(delivery.error, None);
+        // Nothing left to send
+        assert!(Delivery::due(&pool).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn retries_server_errors(pool: PgPool) {
+        let addr = serve().await;
+        let notifier = notifier(pool.clone()).await;
+        let user_id = queue(format!("http://{addr}/down"), &pool).await;
+        for attempt in 1..=MAX_ATTEMPTS {
+            tick(&notifier).await;
+            let (delivery, next_in) = logged(user_id, &pool).await;
+            assert_eq!(delivery.attempts, attempt);
+            assert_eq!(delivery.response_status, Some(502));
+            assert_eq!(
+                delivery.error.as_deref(),
+                Some("Responded with 502 Bad Gateway")
+            );
+            if attempt < MAX_ATTEMPTS {
+                assert_eq!(delivery.status, DeliveryStatus::Pending);
+                let backoff = (BACKOFF * 2u32.pow(attempt as u32 - 1)).as_secs_f64();
+                assert!((backoff - 1.0..=backoff).contains(&next_in), "{next_in}");
+                // Not due yet
+                assert!(Delivery::due(&pool).await.unwrap().is_empty());
+                sqlx::query("update notification_deliveries set next_attempt_at = now()")
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+            } else {
+                assert_eq!(delivery.status, DeliveryStatus::Failed);
+            }
+        }
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn waits_for_retry_after(pool: PgPool) {
+        let addr = serve().await;
+        let notifier = notifier(pool.clone()).await;
+        let user_id = queue(format!("http://{addr}/busy"), &pool).await;
+        tick(&notifier).await;
+        let (delivery, next_in) = logged(user_id, &pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.response_status, Some(429));
+        assert!((29.0..=30.0).contains(&next_in), "{next_in}");
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn gives_up_on_client_errors(pool: PgPool) {
+        let addr = serve().await;
+        let notifier = notifier(pool.clone()).await;
+        let user_id = queue(format!("http://{addr}/gone"), &pool).await;
+        tick(&notifier).await;
+        let (delivery, _) = logged(user_id, &pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.response_status, Some(404));
+        assert_eq!(
+            delivery.error.as_deref(),
+            Some("Responded with 404 Not Found")
+        );
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn refuses_private_addresses(pool: PgPool) {
+        let notifier = notifier(pool.clone()).await;
+        let user_id = queue("http://127.0.0.1:8081/v1/vhosts".to_string(), &pool).await;
+        tick(&notifier).await;
+        let (delivery, _) = logged(user_id, &pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.response_status, None);
+    }
+}
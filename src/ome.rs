@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use reqwest::{Method, StatusCode};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+use crate::recording::RecordState;
+
+const DEFAULT_API_URL: &str = "http://localhost:8081";
+const DEFAULT_VHOST: &str = "default";
+const DEFAULT_APP: &str = "app";
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+type StatsCache = HashMap<String, (Instant, Option<StreamStats>)>;
+
+/// Client for the OvenMediaEngine REST API, so its credentials never have to
+/// leave the server.
+#[derive(Debug, Clone)]
+pub struct Ome {
+    client: reqwest::Client,
+    base_url: String,
+    access_token: Option<String>,
+    vhost: String,
+    app: String,
+    stats: Arc<RwLock<StatsCache>>,
+}
+
+/// Every OME response is wrapped in this.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope<T> {
+    status_code: u16,
+    message: String,
+    response: Option<T>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OmeStreamStats {
+    total_connections: u64,
+    last_throughput_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OmeStream {
+    input: OmeInput,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OmeInput {
+    created_time: DateTime<Utc>,
+    source_type: String,
+    #[serde(default)]
+    tracks: Vec<OmeTrack>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OmeTrack {
+    video: Option<OmeVideo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OmeVideo {
+    // OME sends bitrates as strings
+    bitrate: Option<String>,
+    codec: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    framerate: Option<f64>,
+}
+
+/// A recording as OME reports it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    pub id: String,
+    pub state: RecordState,
+    #[serde(alias = "outputFilePath")]
+    pub file_path: Option<String>,
+    #[serde(alias = "outputInfoPath")]
+    pub info_path: Option<String>,
+    pub total_record_bytes: Option<i64>,
+    /// Milliseconds
+    pub total_record_time: Option<i64>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub finish_time: Option<DateTime<Utc>>,
+}
+
+/// What we tell clients about a live stream.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamStats {
+    pub viewers: u64,
+    /// Incoming bits per second, measured by OME
+    pub bitrate: Option<u64>,
+    pub source: String,
+    pub video: Option<VideoStats>,
+    pub started_at: DateTime<Utc>,
+    pub uptime: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoStats {
+    pub codec: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub framerate: Option<f64>,
+    /// Configured bitrate of the video track
+    pub bitrate: Option<u64>,
+}
+
+impl Ome {
+    pub fn new(base_url: String, access_token: Option<String>, vhost: String, app: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("Client to be built");
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            access_token,
+            vhost,
+            app,
+            stats: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Configured by `OME_API_URL`, `OME_API_TOKEN`, `OME_VHOST` and `OME_APP`.
+    pub fn from_env() -> Self {
+        Self::new(
+            env::var("OME_API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_string()),
+            env::var("OME_API_TOKEN").ok(),
+            env::var("OME_VHOST").unwrap_or_else(|_| DEFAULT_VHOST.to_string()),
+            env::var("OME_APP").unwrap_or_else(|_| DEFAULT_APP.to_string()),
+        )
+    }
+
+    /// Calls `path` below the API root, `None` if OME doesn't know it.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Option<Envelope<T>>> {
+        let mut req = self
+            .client
+            .request(method, format!("{}/v1/{path}", self.base_url));
+        if let Some(ref token) = self.access_token {
+            req = req.basic_auth(token, None::<&str>);
+        }
+        if let Some(body) = body {
+            req = req.json(&body);
+        }
+        let res = req.send().await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body: Envelope<T> = res.error_for_status()?.json().await?;
+        match body.status_code {
+            200 => Ok(Some(body)),
+            404 => Ok(None),
+            code => bail!("OME responded with {code}: {}", body.message),
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>> {
+        Ok(self
+            .call(Method::GET, path, None)
+            .await?
+            .and_then(|body| body.response))
+    }
+
+    /// Whether the API answers at all.
+    pub async fn reachable(&self) -> Result<()> {
+        self.get::<IgnoredAny>("vhosts").await.map(|_| ())
+    }
+
+    /// Terminates the ingest of a stream and everyone watching it. `false` if
+    /// it wasn't live.
+    pub async fn stop_stream(&self, stream: &str) -> Result<bool> {
+        let deleted = self
+            .call::<IgnoredAny>(Method::DELETE, &self.stream_path(stream)?, None)
+            .await?;
+        self.stats.write().await.remove(stream);
+        Ok(deleted.is_some())
+    }
+
+    /// Calls one of the app's `:<action>` endpoints. They all answer with a
+    /// list of recordings.
+    async fn record_action(&self, action: &str, body: Value) -> Result<Option<Record>> {
+        let path = format!("vhosts/{}/apps/{}:{action}", self.vhost, self.app);
+        let records = self
+            .call::<Vec<Record>>(Method::POST, &path, Some(body))
+            .await?
+            .and_then(|body| body.response);
+        Ok(records.and_then(|r| r.into_iter().next()))
+    }
+
+    /// Starts recording `stream` under `id`, `None` if the stream isn't live.
+    pub async fn start_record(&self, id: &str, stream: &str) -> Result<Option<Record>> {
+        self.record_action(
+            "startRecord",
+            json!({ "id": id, "stream": { "name": stream } }),
+        )
+        .await
+    }
+
+    pub async fn stop_record(&self, id: &str) -> Result<Option<Record>> {
+        self.record_action("stopRecord", json!({ "id": id })).await
+    }
+
+    /// Current state of a recording, `None` once OME forgot about it.
+    pub async fn record(&self, id: &str) -> Result<Option<Record>> {
+        self.record_action("records", json!({ "id": id })).await
+    }
+
+    /// Usernames are not restricted, but have to stay a single path segment
+    /// to not reach other parts of the API.
+    fn stream_path(&self, stream: &str) -> Result<String> {
+        if stream.is_empty()
+            || stream == "."
+            || stream == ".."
+            || stream.contains(['/', '\\', '?', '#', '%'])
+        {
+            bail!("Invalid stream name {stream:?}");
+        }
+        Ok(format!(
+            "vhosts/{}/apps/{}/streams/{stream}",
+            self.vhost, self.app
+        ))
+    }
+
+    /// Stats of a live stream, `None` if it is offline. Cached for
+    /// [`CACHE_TTL`], expired entries are dropped whenever one is added.
+    pub async fn stream_stats(&self, stream: &str) -> Result<Option<StreamStats>> {
+        if let Some((fetched, stats)) = self.stats.read().await.get(stream) {
+            if fetched.elapsed() < CACHE_TTL {
+                return Ok(stats.clone());
+            }
+        }
+        let stats = self.fetch_stream_stats(stream).await?;
+        let mut cache = self.stats.write().await;
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < CACHE_TTL);
+        cache.insert(stream.to_string(), (Instant::now(), stats.clone()));
+        Ok(stats)
+    }
+
+    async fn fetch_stream_stats(&self, stream: &str) -> Result<Option<StreamStats>> {
+        let path = self.stream_path(stream)?;
+        let Some(info) = self.get::<OmeStream>(&path).await? else {
+            return Ok(None);
+        };
+        let Some(stats) = self
+            .get::<OmeStreamStats>(&format!("stats/current/{path}"))
+            .await?
+        else {
+            return Ok(None);
+        };
+        let video = info
+            .input
+            .tracks
+            .into_iter()
+            .find_map(|t| t.video)
+            .map(|v| VideoStats {
+                codec: v.codec,
+                width: v.width,
+                height: v.height,
+                framerate: v.framerate,
+                bitrate: v.bitrate.and_then(|b| b.parse().ok()),
+            });
+        Ok(Some(StreamStats {
+            viewers: stats.total_connections,
+            bitrate: stats.last_throughput_in,
+            source: info.input.source_type,
+            video,
+            started_at: info.input.created_time,
+            uptime: (Utc::now() - info.input.created_time).num_seconds().max(0),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{header::AUTHORIZATION, HeaderMap, Method, StatusCode, Uri},
+        response::{IntoResponse, Response},
+        Json, Router,
+    };
+
+    use super::*;
+
+    const TOKEN: &str = "secret";
+
+    /// Stand-in for the parts of the OME API we use. `alice` is live, `bob`
+    /// is unknown to OME, `carol` is only missing according to the envelope
+    /// and `dave` makes OME fail.
+    async fn api(
+        State(calls): State<Arc<AtomicUsize>>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        calls.fetch_add(1, Ordering::Relaxed);
+        // basic auth of the token without a password
+        if headers.get(AUTHORIZATION).and_then(|h| h.to_str().ok()) != Some("Basic c2VjcmV0Og==") {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        let body: Value = serde_json::from_slice(&body).unwrap_or_default();
+        let envelope = |status: u16, response: Value| {
+            Json(json!({ "statusCode": status, "message": "OK", "response": response }))
+                .into_response()
+        };
+        let record = |state: &str| {
+            json!([{
+                "id": body["id"],
+                "state": state,
+                "outputFilePath": "/rec/alice.ts",
+                "totalRecordBytes": 1024,
+                "totalRecordTime": 60000,
+            }])
+        };
+        match (method, uri.path().trim_start_matches("/v1/")) {
+            (Method::GET, "vhosts") => envelope(200, json!(["default"])),
+            (Method::GET, "vhosts/default/apps/app/streams/alice") => envelope(
+                200,
+                json!({ "input": {
+                    "createdTime": Utc::now() - chrono::Duration::hours(1),
+                    "sourceType": "Rtmp",
+                    "tracks": [
+                        { "audio": {} },
+                        { "video": { "bitrate": "2500000", "codec": "H264", "width": 1920, "height": 1080, "framerate": 30.0 } }
+                    ],
+                }}),
+            ),
+            (Method::GET, "stats/current/vhosts/default/apps/app/streams/alice") => envelope(
+                200,
+                json!({ "totalConnections": 3, "lastThroughputIn": 2600000 }),
+            ),
+            (Method::GET, "vhosts/default/apps/app/streams/carol") => envelope(404, Value::Null),
+            (Method::GET, "vhosts/default/apps/app/streams/dave") => {
+                Json(json!({ "statusCode": 500, "message": "Internal error" })).into_response()
+            }
+            (Method::DELETE, "vhosts/default/apps/app/streams/alice") => envelope(200, json!([])),
+            (Method::POST, "vhosts/default/apps/app:startRecord") => {
+                if body["stream"]["name"] == "alice" {
+                    envelope(200, record("ready"))
+                } else {
+                    // What OME answers for streams it doesn't know
+                    StatusCode::NOT_FOUND.into_response()
+                }
+            }
+            (Method::POST, "vhosts/default/apps/app:stopRecord") => {
+                envelope(200, record("stopping"))
+            }
+            (Method::POST, "vhosts/default/apps/app:records") => match body["id"].as_str() {
+                Some("rec") => envelope(200, record("stopped")),
+                _ => envelope(200, json!([])),
+            },
+            _ => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    async fn serve(token: Option<&str>) -> (Ome, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().fallback(api).with_state(calls.clone());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        let ome = Ome::new(
+            url,
+            token.map(str::to_string),
+            DEFAULT_VHOST.to_string(),
+            DEFAULT_APP.to_string(),
+        );
+        (ome, calls)
+    }
+
+    #[tokio::test]
+    async fn stream_stats() {
+        let (ome, _) = serve(Some(TOKEN)).await;
+        let stats = ome
+            .stream_stats("alice")
+            .await
+            .unwrap()
+            .expect("alice is live");
+        assert_eq!(stats.viewers, 3);
+        assert_eq!(stats.bitrate, Some(2600000));
+        assert_eq!(stats.source, "Rtmp");
+        let video = stats.video.expect("a video track");
+        assert_eq!(video.codec.as_deref(), Some("H264"));
+        assert_eq!((video.width, video.height), (Some(1920), Some(1080)));
+        assert_eq!(video.bitrate, Some(2500000));
+        assert!((3599..=3601).contains(&stats.uptime));
+    }
+
+    #[tokio::test]
+    async fn missing_streams() {
+        let (ome, _) = serve(Some(TOKEN)).await;
+        // A plain 404 and one in the envelope
+        assert!(ome.stream_stats("bob").await.unwrap().is_none());
+        assert!(ome.stream_stats("carol").await.unwrap().is_none());
+        let err = ome.stream_stats("dave").await.unwrap_err();
+        assert!(err.to_string().contains("500"), "{err}");
+        assert!(ome.stream_stats("../vhosts").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn caches_stats() {
+        let (ome, calls) = serve(Some(TOKEN)).await;
+        ome.stream_stats("alice").await.unwrap();
+        ome.stream_stats("alice").await.unwrap();
+        // Stream info and stats, only once
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        ome.stream_stats("bob").await.unwrap();
+        ome.stream_stats("bob").await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        // Stopping drops the stats of the stream
+        assert!(ome.stop_stream("alice").await.unwrap());
+        ome.stream_stats("alice").await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn needs_the_token() {
+        let (ome, _) = serve(None).await;
+        assert!(ome.reachable().await.is_err());
+        let (ome, _) = serve(Some(TOKEN)).await;
+        ome.reachable().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_stream() {
+        let (ome, _) = serve(Some(TOKEN)).await;
+        assert!(ome.stop_stream("alice").await.unwrap());
+        assert!(!ome.stop_stream("bob").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn records() {
+        let (ome, _) = serve(Some(TOKEN)).await;
+        let record = ome.start_record("rec", "alice").await.unwrap().unwrap();
+        assert_eq!(record.id, "rec");
+        assert_eq!(record.state, RecordState::Ready);
+        assert!(ome.start_record("rec", "bob").await.unwrap().is_none());
+
+        let record = ome.stop_record("rec").await.unwrap().unwrap();
+        assert_eq!(record.state, RecordState::Stopping);
+
+        let record = ome.record("rec").await.unwrap().unwrap();
+        assert_eq!(record.state, RecordState::Stopped);
+        assert_eq!(record.file_path.as_deref(), Some("/rec/alice.ts"));
+        assert_eq!(record.total_record_bytes, Some(1024));
+        assert_eq!(record.total_record_time, Some(60000));
+        assert!(ome.record("gone").await.unwrap().is_none());
+    }
+}
@@ -1,10 +1,30 @@
 use serde::{Deserialize, Serialize};
 use sqlx::{PgPool, Result};
 
+use crate::feed::{self, StreamEvent};
+
 #[derive(Debug, Serialize, Default)]
 pub struct PublicOptions {
     pub name: Option<String>,
     pub emote_id: Option<String>,
+    pub followers: i64,
+}
+
+/// Who may chat while the stream is offline. The owner always can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum OfflineChat {
+    Open,
+    Followers,
+    ReadOnly,
+}
+
+/// What the chat needs to know about the stream.
+#[derive(Debug)]
+pub struct ChatOptions {
+    pub live: bool,
+    pub offline_chat: OfflineChat,
 }
 
 #[derive(Debug, Serialize)]
@@ -13,6 +33,9 @@ pub struct StreamOptions {
     emote_id: Option<String>,
     token: String,
     public: bool,
+    offline_chat: OfflineChat,
+    /// Start recording whenever the stream goes live
+    record: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,10 +45,25 @@ pub struct UpdateStreamOptions {
     #[serde(default)]
     token: bool,
     public: Option<bool>,
+    offline_chat: Option<OfflineChat>,
+    record: Option<bool>,
 }
 
 impl UpdateStreamOptions {
+    /// Whether the update gives the user a new stream key.
+    pub fn rotates_token(&self) -> bool {
+        self.token
+    }
+
     pub async fn update(&self, user_id: i32, pool: &PgPool) -> Result<StreamOptions> {
+        let previous = match self.name {
+            Some(_) => {
+                sqlx::query_scalar!("select name from options where user_id = $1", user_id)
+                    .fetch_one(pool)
+                    .await?
+            }
+            None => None,
+        };
         let so = sqlx::query_as!(
             StreamOptions,
             r#"--sql
@@ -36,69 +74,127 @@ impl UpdateStreamOptions {
                 token = case when $4
                     then MD5(random()::text)
                     else token
-                    end
-            where user_id = $5
-            returning name, emote_id, public, token
+                    end,
+                offline_chat = coalesce($5, offline_chat),
+                record = coalesce($6, record)
+            where user_id = $7
+            returning name, emote_id, public, token, offline_chat as "offline_chat: _", record
             "#,
             self.name,
             self.emote_id,
             self.public,
             self.token,
+            self.offline_chat as _,
+            self.record,
             user_id
         )
         .fetch_one(pool)
         .await?;
+        // Only an actual change is worth telling everyone about
+        if self.name.is_some() && so.name != previous {
+            let event = StreamEvent::Title {
+                name: so.name.clone(),
+            };
+            if let Err(e) = feed::publish(user_id, &event, pool).await {
+                tracing::error!(%e, user_id, "Failed to publish title change");
+            }
+        }
         Ok(so)
     }
 }
 
 impl StreamOptions {
     pub async fn from_user_id(user_id: i32, pool: &PgPool) -> Result<Self> {
-        Ok(sqlx::query_as!(
+        sqlx::query_as!(
             Self,
             r#"--sql
                 select
                     name,
                     emote_id,
                     token,
-                    public
+                    public,
+                    offline_chat as "offline_chat: _",
+                    record
                 from options where user_id = $1
                "#,
             user_id
         )
         .fetch_one(pool)
-        .await?)
+        .await
+    }
+
+    /// Replaces the stream key, so it can't be used to publish anymore.
+    pub async fn rotate_token(user_id: i32, pool: &PgPool) -> Result<Self> {
+        UpdateStreamOptions {
+            name: None,
+            emote_id: None,
+            token: true,
+            public: None,
+            offline_chat: None,
+            record: None,
+        }
+        .update(user_id, pool)
+        .await
+    }
+
+    pub async fn records_by_default(user_id: i32, pool: &PgPool) -> Result<bool> {
+        let row = sqlx::query!("select record from options where user_id = $1", user_id)
+            .fetch_one(pool)
+            .await?;
+        Ok(row.record)
     }
 
     pub async fn create(user_id: i32, pool: &PgPool) -> Result<Self> {
-        Ok(sqlx::query_as!(
+        sqlx::query_as!(
             Self,
             r#"--sql
             insert into options (user_id, token)
             values ($1, MD5(random()::text))
-            returning name, emote_id, token, public
+            returning name, emote_id, token, public, offline_chat as "offline_chat: _", record
             "#,
             user_id
         )
         .fetch_one(pool)
-        .await?)
+        .await
     }
 }
 
 impl PublicOptions {
     pub async fn from_username(username: &str, pool: &PgPool) -> Result<Self> {
-        Ok(sqlx::query_as!(
+        sqlx::query_as!(
             Self,
             r#"--sql
                 select
                     name,
-                    emote_id
+                    emote_id,
+                    (select count(*) from follows where streamer_id = user_id) as "followers!"
                 from options
-                where user_id = (select id from users where username = $1) 
+                where user_id = (select id from users where username = $1)
                 "#,
             username
         )
         .fetch_one(pool)
-        .await?)
+        .await
+    }
+}
+
+impl ChatOptions {
+    pub async fn from_username(username: &str, pool: &PgPool) -> Result<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+                select
+                    exists(
+                        select 1 from stream_sessions s
+                        where s.user_id = o.user_id and s.ended_at is null
+                    ) as "live!",
+                    o.offline_chat as "offline_chat: _"
+                from options o
+                where o.user_id = (select id from users where username = $1)
+                "#,
+            username
+        )
+        .fetch_optional(pool)
+        .await
     }
 }
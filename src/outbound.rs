@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use url::{Host, Url};
+
+/// Guards requests to user supplied URLs.
+#[derive(Debug, Clone, Default)]
+pub struct Outbound {
+    /// Hosts that may be targeted even though they are not public
+    allowed: Arc<HashSet<String>>,
+}
+
+impl Outbound {
+    /// Allows the hosts in `OUTBOUND_ALLOWED_HOSTS`, comma separated.
+    pub fn from_env() -> Self {
+        let hosts = env::var("OUTBOUND_ALLOWED_HOSTS").unwrap_or_default();
+        Self::allowing(hosts.split(','))
+    }
+
+    pub fn allowing<'a>(hosts: impl IntoIterator<Item = &'a str>) -> Self {
+        let allowed = hosts
+            .into_iter()
+            .map(|host| host.trim().trim_start_matches('[').trim_end_matches(']'))
+            .filter(|host| !host.is_empty())
+            .map(str::to_ascii_lowercase)
+            .collect();
+        Self {
+            allowed: Arc::new(allowed),
+        }
+    }
+
+    fn is_allowed(&self, host: &str) -> bool {
+        self.allowed.contains(&host.to_ascii_lowercase())
+    }
+
+    /// Whether a user supplied URL may be posted to. Names are checked
+    /// again once resolved, see [`Outbound::client`].
+    pub fn permitted(&self, url: &Url) -> bool {
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+        match url.host() {
+            Some(Host::Domain(domain)) => {
+                self.is_allowed(domain)
+                    || !domain
+                        .trim_end_matches('.')
+                        .eq_ignore_ascii_case("localhost")
+            }
+            Some(Host::Ipv4(ip)) => self.is_allowed(&ip.to_string()) || is_public(ip.into()),
+            Some(Host::Ipv6(ip)) => self.is_allowed(&ip.to_string()) || is_public(ip.into()),
+            None => false,
+        }
+    }
+
+    /// Client for requests to user supplied URLs. Redirects are not
+    /// followed, they could lead anywhere.
+    pub fn client(&self, timeout: Duration) -> reqwest::Client {
+        reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(Policy::none())
+            .dns_resolver(Arc::new(PublicResolver(self.clone())))
+            .build()
+            .expect("Client to be built")
+    }
+}
+
+/// Whether an address is reachable from the internet, so that users can't
+/// make us post to ourselves, OME's API or anything else on the local network.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network", carrier-grade NAT, benchmarking and reserved
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local, link local, documentation, NAT64 and IPv4-compatible
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && second == 0x0db8)
+        || (first == 0x0064 && second == 0xff9b)
+        || ip.to_ipv4().is_some())
+}
+
+/// Resolves names like the system does, but only to public addresses.
+struct PublicResolver(Outbound);
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let outbound = self.0.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| outbound.is_allowed(host) || is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{host} does not resolve to a public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn urls() {
+        let outbound = Outbound::default();
+        let permitted = |url: &str| outbound.permitted(&Url::parse(url).unwrap());
+        assert!(permitted("https://example.com/hook"));
+        assert!(permitted("http://1.1.1.1/"));
+        assert!(!permitted("ftp://example.com/"));
+        assert!(!permitted("http://localhost:8081/v1/vhosts"));
+        assert!(!permitted("http://LOCALHOST./"));
+        assert!(!permitted("http://127.0.0.1:8081/"));
+        assert!(!permitted("http://[::1]/"));
+        assert!(!permitted("http://169.254.169.254/latest/meta-data"));
+        // Same address, written differently
+        assert!(!permitted("http://2130706433/"));
+        assert!(!permitted("http://0x7f.1/"));
+    }
+
+    #[test]
+    fn allowed_hosts() {
+        let outbound = Outbound::allowing("hooks.internal, 10.0.0.5,[::1]".split(','));
+        let permitted = |url: &str| outbound.permitted(&Url::parse(url).unwrap());
+        assert!(permitted("http://10.0.0.5:8080/"));
+        assert!(permitted("http://[::1]/"));
+        assert!(!permitted("http://10.0.0.6/"));
+        assert!(!permitted("http://localhost/"));
+    }
+
+    #[tokio::test]
+    async fn resolves_to_public_addresses_only() {
+        let resolver = PublicResolver(Outbound::default());
+        assert!(resolver
+            .resolve("localhost".parse().unwrap())
+            .await
+            .is_err());
+        let allowed = PublicResolver(Outbound::allowing(["localhost"]));
+        assert!(allowed.resolve("localhost".parse().unwrap()).await.is_ok());
+        let err = Outbound::default()
+            .client(Duration::from_secs(1))
+            .post("http://localhost:1/")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_connect(), "{err}");
+    }
+}
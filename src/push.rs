@@ -0,0 +1,674 @@
+use std::{env, fmt, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_login::RequireAuthorizationLayer;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use futures_util::{stream, StreamExt};
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM},
+    agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, ECDH_P256},
+    error::Unspecified,
+    hkdf::{self, KeyType, Prk, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use url::Url;
+
+use crate::{error::OvenauthError, outbound::Outbound, user::User};
+
+/// Seconds the push service keeps trying, nobody cares about a stream that
+/// went live hours ago
+const TTL: u32 = 60 * 60;
+const RECORD_SIZE: u32 = 4096;
+/// How long VAPID tokens are valid, at most 24 hours are allowed
+const TOKEN_LIFETIME: i64 = 12 * 60 * 60;
+/// Messages sent at once when a stream goes live
+const CONCURRENCY: usize = 16;
+/// Browsers a user can get notifications in
+const MAX_SUBSCRIPTIONS: i64 = 10;
+
+struct Vapid {
+    key: EcdsaKeyPair,
+    /// Uncompressed public key, base64url encoded for browsers
+    public_key: String,
+    /// Contact for push services, a `mailto:` or `https:` URL
+    subject: String,
+}
+
+impl fmt::Debug for Vapid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vapid")
+            .field("public_key", &self.public_key)
+            .field("subject", &self.subject)
+            .finish()
+    }
+}
+
+/// Sends Web Push messages to the subscriptions browsers gave us.
+#[derive(Debug, Clone)]
+pub struct Push {
+    client: reqwest::Client,
+    outbound: Outbound,
+    pool: PgPool,
+    vapid: Arc<Vapid>,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    id: i64,
+    endpoint: String,
+    p256dh: Vec<u8>,
+    auth: Vec<u8>,
+}
+
+/// A `PushSubscription` as browsers serialize it.
+#[derive(Debug, Deserialize)]
+struct NewSubscription {
+    endpoint: String,
+    keys: SubscriptionKeys,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Unsubscribe {
+    endpoint: String,
+}
+
+/// Length of HKDF output.
+struct Len(usize);
+
+impl KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn expand(prk: &Prk, info: &[u8], len: usize) -> Result<Vec<u8>, Unspecified> {
+    let mut out = vec![0; len];
+    prk.expand(&[info], Len(len))?.fill(&mut out)?;
+    Ok(out)
+}
+
+/// Encrypts a push message as a single `aes128gcm` record (RFC 8291).
+fn encrypt(ua_public: &[u8], auth: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Unspecified> {
+    let rng = SystemRandom::new();
+    let as_private = EphemeralPrivateKey::generate(&ECDH_P256, &rng)?;
+    let as_public = as_private.compute_public_key()?;
+    let mut salt = [0; 16];
+    rng.fill(&mut salt)?;
+    agree_ephemeral(
+        as_private,
+        &UnparsedPublicKey::new(&ECDH_P256, ua_public),
+        Unspecified,
+        |ecdh_secret| {
+            seal(
+                ecdh_secret,
+                ua_public,
+                as_public.as_ref(),
+                auth,
+                &salt,
+                plaintext,
+            )
+        },
+    )
+}
+
+/// The part of [`encrypt`] after the key agreement.
+fn seal(
+    ecdh_secret: &[u8],
+    ua_public: &[u8],
+    as_public: &[u8],
+    auth: &[u8],
+    salt: &[u8; 16],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Unspecified> {
+    let prk = hkdf::Salt::new(HKDF_SHA256, auth).extract(ecdh_secret);
+    let info = [b"WebPush: info\0", ua_public, as_public].concat();
+    let ikm = expand(&prk, &info, 32)?;
+    let prk = hkdf::Salt::new(HKDF_SHA256, salt).extract(&ikm);
+    let cek = expand(&prk, b"Content-Encoding: aes128gcm\0", 16)?;
+    let nonce = expand(&prk, b"Content-Encoding: nonce\0", 12)?;
+
+    let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &cek)?);
+    let mut record = plaintext.to_vec();
+    // Delimiter of the last record, no padding
+    record.push(2);
+    key.seal_in_place_append_tag(
+        Nonce::try_assume_unique_for_key(&nonce)?,
+        Aad::empty(),
+        &mut record,
+    )?;
+
+    let mut body = Vec::with_capacity(16 + 4 + 1 + as_public.len() + record.len());
+    body.extend_from_slice(salt);
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.len() as u8);
+    body.extend_from_slice(as_public);
+    body.extend_from_slice(&record);
+    Ok(body)
+}
+
+/// What became of a new subscription.
+#[derive(Debug, PartialEq, Eq)]
+enum Subscribed {
+    Stored,
+    /// The user already has [`MAX_SUBSCRIPTIONS`]
+    TooMany,
+    /// The endpoint belongs to someone else's subscription
+    Taken,
+}
+
+impl Subscription {
+    /// Browsers that subscribe again only update theirs.
+    async fn create(
+        user_id: i32,
+        endpoint: &str,
+        p256dh: &[u8],
+        auth: &[u8],
+        pool: &PgPool,
+    ) -> sqlx::Result<Subscribed> {
+        let row = sqlx::query!(
+            r#"--sql
+            insert into push_subscriptions (user_id, endpoint, p256dh, auth)
+            select $1, $2, $3, $4
+            where (
+                select count(*) from push_subscriptions where user_id = $1 and endpoint <> $2
+            ) < $5
+            on conflict (endpoint) do update
+            set p256dh = excluded.p256dh, auth = excluded.auth
+            where push_subscriptions.user_id = excluded.user_id
+            returning id
+            "#,
+            user_id,
+            endpoint,
+            p256dh,
+            auth,
+            MAX_SUBSCRIPTIONS
+        )
+        .fetch_optional(pool)
+        .await?;
+        if row.is_some() {
+            return Ok(Subscribed::Stored);
+        }
+        let owner = sqlx::query_scalar!(
+            "select user_id from push_subscriptions where endpoint = $1",
+            endpoint
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(match owner {
+            Some(owner) if owner != user_id => Subscribed::Taken,
+            _ => Subscribed::TooMany,
+        })
+    }
+}
+
+impl Vapid {
+    /// `Authorization` header for a push service.
+    fn authorization(&self, endpoint: &Url) -> Result<String> {
+        let header = URL_SAFE_NO_PAD.encode(json!({ "typ": "JWT", "alg": "ES256" }).to_string());
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "aud": endpoint.origin().ascii_serialization(),
+                "exp": Utc::now().timestamp() + TOKEN_LIFETIME,
+                "sub": self.subject,
+            })
+            .to_string(),
+        );
+        let message = format!("{header}.{claims}");
+        let signature = self
+            .key
+            .sign(&SystemRandom::new(), message.as_bytes())
+            .map_err(|_| anyhow!("Failed to sign VAPID token"))?;
+        let signature = URL_SAFE_NO_PAD.encode(signature.as_ref());
+        Ok(format!(
+            "vapid t={message}.{signature}, k={}",
+            self.public_key
+        ))
+    }
+}
+
+impl Push {
+    /// Loads the VAPID key, the first instance to start creates it. The
+    /// contact is `VAPID_SUBJECT`, falling back to `PUBLIC_URL`.
+    pub async fn new(pool: PgPool, outbound: Outbound) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let generated = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .map_err(|_| anyhow!("Failed to generate VAPID key"))?;
+        sqlx::query!(
+            "insert into vapid_keys (pkcs8) values ($1) on conflict do nothing",
+            generated.as_ref()
+        )
+        .execute(&pool)
+        .await?;
+        let row = sqlx::query!("select pkcs8 from vapid_keys")
+            .fetch_one(&pool)
+            .await?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &row.pkcs8)
+            .map_err(|e| anyhow!("Invalid VAPID key: {e}"))?;
+        let subject = env::var("VAPID_SUBJECT")
+            .or_else(|_| env::var("PUBLIC_URL"))
+            .unwrap_or_else(|_| "mailto:admin@localhost".to_string());
+        let client = outbound.client(Duration::from_secs(10));
+        Ok(Self {
+            client,
+            outbound,
+            pool,
+            vapid: Arc::new(Vapid {
+                public_key: URL_SAFE_NO_PAD.encode(key.public_key().as_ref()),
+                key,
+                subject,
+            }),
+        })
+    }
+
+    /// Pushes `payload` to every browser of everyone following the streamer,
+    /// a few at a time. Nothing is retried, so there is nothing to queue.
+    pub async fn notify_followers(&self, streamer_id: i32, payload: &Value) -> Result<()> {
+        let subscriptions = sqlx::query_as!(
+            Subscription,
+            r#"--sql
+            select id, endpoint, p256dh, auth
+            from push_subscriptions
+            where user_id in (select follower_id from follows where streamer_id = $1)
+            "#,
+            streamer_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let payload = payload.to_string();
+        stream::iter(subscriptions)
+            .for_each_concurrent(CONCURRENCY, |subscription| {
+                let payload = &payload;
+                async move {
+                    if let Err(e) = self.send(&subscription, payload.as_bytes()).await {
+                        tracing::warn!(%e, subscription.endpoint, "Failed to push");
+                    }
+                }
+            })
+            .await;
+        Ok(())
+    }
+
+    /// Sends one message, dropping the subscription if the push service
+    /// says it is gone.
+    async fn send(&self, subscription: &Subscription, payload: &[u8]) -> Result<()> {
+        let endpoint = Url::parse(&subscription.endpoint)?;
+        if !self.outbound.permitted(&endpoint) {
+            bail!("Refusing to push to a private address");
+        }
+        let body = encrypt(&subscription.p256dh, &subscription.auth, payload)
+            .map_err(|_| anyhow!("Failed to encrypt push message"))?;
+        let res = self
+            .client
+            .post(endpoint.clone())
+            .header("TTL", TTL)
+            .header("Urgency", "high")
+            .header(CONTENT_ENCODING, "aes128gcm")
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(AUTHORIZATION, self.vapid.authorization(&endpoint)?)
+            .body(body)
+            .send()
+            .await?;
+        match res.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                tracing::debug!(subscription.endpoint, "Pruning expired push subscription");
+                sqlx::query!(
+                    "delete from push_subscriptions where id = $1",
+                    subscription.id
+                )
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+            status => Err(anyhow!("Push service responded with {status}")),
+        }
+    }
+}
+
+// ROUTES
+async fn key(Extension(push): Extension<Push>) -> impl IntoResponse {
+    Json(json!({ "key": push.vapid.public_key }))
+}
+
+async fn subscribe(
+    Extension(user): Extension<User>,
+    Extension(outbound): Extension<Outbound>,
+    State(db): State<PgPool>,
+    Json(subscription): Json<NewSubscription>,
+) -> Result<Response, OvenauthError> {
+    let p256dh = URL_SAFE_NO_PAD.decode(subscription.keys.p256dh.trim_end_matches('='));
+    let auth = URL_SAFE_NO_PAD.decode(subscription.keys.auth.trim_end_matches('='));
+    let endpoint = Url::parse(&subscription.endpoint);
+    let (Ok(p256dh), Ok(auth), Ok(endpoint)) = (p256dh, auth, endpoint) else {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid subscription").into_response());
+    };
+    if p256dh.len() != 65 || auth.len() != 16 || !outbound.permitted(&endpoint) {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid subscription").into_response());
+    }
+    match Subscription::create(user.id, &subscription.endpoint, &p256dh, &auth, &db).await? {
+        Subscribed::Stored => Ok(Json(json!({ "subscribed": true })).into_response()),
+        Subscribed::TooMany => {
+            Ok((StatusCode::CONFLICT, "Too many push subscriptions").into_response())
+        }
+        Subscribed::Taken => {
+            Ok((StatusCode::CONFLICT, "Subscribed by another user").into_response())
+        }
+    }
+}
+
+async fn unsubscribe(
+    Extension(user): Extension<User>,
+    State(db): State<PgPool>,
+    Query(subscription): Query<Unsubscribe>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    sqlx::query!(
+        "delete from push_subscriptions where user_id = $1 and endpoint = $2",
+        user.id,
+        subscription.endpoint
+    )
+    .execute(&db)
+    .await?;
+    Ok(Json(json!({ "subscribed": false })))
+}
+
+pub fn routes() -> Router<PgPool> {
+    Router::new()
+        .route("/subscriptions", post(subscribe).delete(unsubscribe))
+        .route_layer(RequireAuthorizationLayer::<i32, User>::login())
+        .route("/key", get(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        body::Bytes,
+        http::{HeaderMap, Uri},
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn b64(s: &str) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(s).unwrap()
+    }
+
+    /// What a browser does with a push message.
+    fn decrypt(
+        ua_private: EphemeralPrivateKey,
+        ua_public: &[u8],
+        auth: &[u8],
+        body: &[u8],
+    ) -> Vec<u8> {
+        let (salt, rest) = body.split_at(16);
+        let (record_size, rest) = rest.split_at(4);
+        assert_eq!(
+            u32::from_be_bytes(record_size.try_into().unwrap()),
+            RECORD_SIZE
+        );
+        let (as_public, ciphertext) = rest[1..].split_at(usize::from(rest[0]));
+        let ikm = agree_ephemeral(
+            ua_private,
+            &UnparsedPublicKey::new(&ECDH_P256, as_public),
+            Unspecified,
+            |ecdh_secret| {
+                let prk = hkdf::Salt::new(HKDF_SHA256, auth).extract(ecdh_secret);
+                let info = [b"WebPush: info\0", ua_public, as_public].concat();
+                expand(&prk, &info, 32)
+            },
+        )
+        .unwrap();
+        let prk = hkdf::Salt::new(HKDF_SHA256, salt).extract(&ikm);
+        let cek = expand(&prk, b"Content-Encoding: aes128gcm\0", 16).unwrap();
+        let nonce = expand(&prk, b"Content-Encoding: nonce\0", 12).unwrap();
+        let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &cek).unwrap());
+        let mut record = ciphertext.to_vec();
+        let plaintext = key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(&nonce).unwrap(),
+                Aad::empty(),
+                &mut record,
+            )
+            .unwrap();
+        assert_eq!(plaintext.last(), Some(&2), "last record delimiter");
+        plaintext[..plaintext.len() - 1].to_vec()
+    }
+
+    /// A browser's subscription keys.
+    fn browser() -> (EphemeralPrivateKey, Vec<u8>, Vec<u8>) {
+        let rng = SystemRandom::new();
+        let private = EphemeralPrivateKey::generate(&ECDH_P256, &rng).unwrap();
+        let public = private.compute_public_key().unwrap().as_ref().to_vec();
+        let mut auth = vec![0; 16];
+        rng.fill(&mut auth).unwrap();
+        (private, public, auth)
+    }
+
+    /// The example from RFC 8291, Appendix A.
+    #[test]
+    fn seals_like_the_rfc() {
+        let body = seal(
+            &b64("kyrL1jIIOHEzg3sM2ZWRHDRB62YACZhhSlknJ672kSs"),
+            &b64("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"),
+            &b64("BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"),
+            &b64("BTBZMqHH6r4Tts7J_aSIgg"),
+            &b64("DGv6ra1nlYgDCS1FRnbzlw").try_into().unwrap(),
+            b"When I grow up, I want to be a watermelon",
+        )
+        .unwrap();
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+        );
+    }
+
+    #[test]
+    fn browsers_can_decrypt() {
+        let (private, public, auth) = browser();
+        let body = encrypt(&public, &auth, b"{\"title\":\"alice went live\"}").unwrap();
+        assert_eq!(
+            decrypt(private, &public, &auth, &body),
+            b"{\"title\":\"alice went live\"}"
+        );
+    }
+
+    /// Stand-in for a push service, passes on what it was sent.
+    async fn push_service(
+        State(sent): State<mpsc::UnboundedSender<(HeaderMap, Bytes)>>,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        sent.send((headers, body)).unwrap();
+        match uri.path() {
+            "/gone" => StatusCode::GONE,
+            "/expired" => StatusCode::NOT_FOUND,
+            "/down" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::CREATED,
+        }
+    }
+
+    async fn serve() -> (SocketAddr, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new().fallback(push_service).with_state(tx);
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 2], 0)))
+            .serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, rx)
+    }
+
+    async fn user(pool: &PgPool) -> i32 {
+        sqlx::query_scalar(
+            "insert into users (username, password) values ('alice', '') returning id",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn subscription(user_id: i32, endpoint: String, pool: &PgPool) -> Subscription {
+        let (_, p256dh, auth) = browser();
+        assert_eq!(
+            Subscription::create(user_id, &endpoint, &p256dh, &auth, pool)
+                .await
+                .unwrap(),
+            Subscribed::Stored
+        );
+        sqlx::query_as!(
+            Subscription,
+            "select id, endpoint, p256dh, auth from push_subscriptions where endpoint = $1",
+            endpoint
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn endpoints(pool: &PgPool) -> Vec<String> {
+        sqlx::query_scalar("select endpoint from push_subscriptions order by id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn sends_encrypted_messages(pool: PgPool) {
+        let (addr, mut sent) = serve().await;
+        let push = Push::new(pool.clone(), Outbound::allowing(["127.0.0.2"]))
+            .await
+            .unwrap();
+        let user_id = user(&pool).await;
+        let (private, public, auth) = browser();
+        let endpoint = format!("http://{addr}/ok");
+        assert_eq!(
+            Subscription::create(user_id, &endpoint, &public, &auth, &pool)
+                .await
+                .unwrap(),
+            Subscribed::Stored
+        );
+        sqlx::query("insert into users (username, password) values ('bob', '')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("insert into follows (follower_id, streamer_id) select $1, id from users where username = 'bob'")
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let streamer_id = sqlx::query_scalar("select id from users where username = 'bob'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        push.notify_followers(streamer_id, &json!({ "title": "bob went live" }))
+            .await
+            .unwrap();
+        let (headers, body) = sent.recv().await.unwrap();
+        assert_eq!(headers[CONTENT_ENCODING], "aes128gcm");
+        assert_eq!(headers["ttl"], TTL.to_string());
+        let authorization = headers[AUTHORIZATION].to_str().unwrap();
+        assert!(authorization.starts_with("vapid t="), "{authorization}");
+        assert!(authorization.ends_with(&format!(", k={}", push.vapid.public_key)));
+        let message = decrypt(private, &public, &auth, &body);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&message).unwrap(),
+            json!({ "title": "bob went live" })
+        );
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn prunes_expired_subscriptions(pool: PgPool) {
+        let (addr, _sent) = serve().await;
+        let push = Push::new(pool.clone(), Outbound::allowing(["127.0.0.2"]))
+            .await
+            .unwrap();
+        let user_id = user(&pool).await;
+        let mut subscriptions = Vec::new();
+        for path in ["ok", "gone", "expired", "down"] {
+            subscriptions.push(subscription(user_id, format!("http://{addr}/{path}"), &pool).await);
+        }
+        let mut errors = 0;
+        for subscription in &subscriptions {
+            if push.send(subscription, b"{}").await.is_err() {
+                errors += 1;
+            }
+        }
+        // Failing push services are not a reason to drop anything
+        assert_eq!(errors, 1);
+        assert_eq!(
+            endpoints(&pool).await,
+            [format!("http://{addr}/ok"), format!("http://{addr}/down")]
+        );
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn limits_subscriptions(pool: PgPool) {
+        let user_id = user(&pool).await;
+        for i in 0..MAX_SUBSCRIPTIONS {
+            subscription(user_id, format!("https://push.example.com/{i}"), &pool).await;
+        }
+        let (_, p256dh, auth) = browser();
+        let create =
+            |user_id, endpoint| Subscription::create(user_id, endpoint, &p256dh, &auth, &pool);
+        assert_eq!(
+            create(user_id, "https://push.example.com/new")
+                .await
+                .unwrap(),
+            Subscribed::TooMany
+        );
+        // Browsers can still renew theirs
+        assert_eq!(
+            create(user_id, "https://push.example.com/0").await.unwrap(),
+            Subscribed::Stored
+        );
+        assert_eq!(endpoints(&pool).await.len() as i64, MAX_SUBSCRIPTIONS);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn keeps_subscriptions_of_others(pool: PgPool) {
+        let alice = user(&pool).await;
+        let bob: i32 = sqlx::query_scalar(
+            "insert into users (username, password) values ('bob', '') returning id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let endpoint = "https://push.example.com/alice";
+        subscription(alice, endpoint.to_string(), &pool).await;
+        let (_, p256dh, auth) = browser();
+        assert_eq!(
+            Subscription::create(bob, endpoint, &p256dh, &auth, &pool)
+                .await
+                .unwrap(),
+            Subscribed::Taken
+        );
+        let owner: i32 =
+            sqlx::query_scalar("select user_id from push_subscriptions where endpoint = $1")
+                .bind(endpoint)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(owner, alice);
+    }
+}
@@ -0,0 +1,149 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Result};
+use ulid::Ulid;
+
+use crate::{
+    ome::{Ome, Record},
+    user::User,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum RecordState {
+    Ready,
+    Recording,
+    Stopping,
+    Stopped,
+    Error,
+}
+
+/// A server-side recording in OvenMediaEngine, as last reported by it.
+#[derive(Debug, Serialize)]
+pub struct Recording {
+    pub id: i64,
+    pub user_id: i32,
+    pub record_id: String,
+    pub state: RecordState,
+    pub file_path: Option<String>,
+    pub info_path: Option<String>,
+    pub bytes: Option<i64>,
+    /// Milliseconds
+    pub duration: Option<i64>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Recording {
+    /// Stores what OME reported about a recording.
+    pub async fn save(user_id: i32, record: &Record, pool: &PgPool) -> Result<Self> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            insert into recordings (user_id, record_id, state, file_path, info_path, bytes, duration, started_at, finished_at)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            on conflict (record_id) do update
+            set state = excluded.state,
+                file_path = coalesce(excluded.file_path, recordings.file_path),
+                info_path = coalesce(excluded.info_path, recordings.info_path),
+                bytes = coalesce(excluded.bytes, recordings.bytes),
+                duration = coalesce(excluded.duration, recordings.duration),
+                started_at = coalesce(excluded.started_at, recordings.started_at),
+                finished_at = coalesce(excluded.finished_at, recordings.finished_at)
+            returning id, user_id, record_id, state as "state: _", file_path, info_path,
+                bytes, duration, started_at, finished_at, created_at
+            "#,
+            user_id,
+            record.id,
+            record.state as _,
+            record.file_path,
+            record.info_path,
+            record.total_record_bytes,
+            record.total_record_time,
+            record.start_time,
+            record.finish_time
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Recordings OME hasn't finished yet, newest first.
+    pub async fn unfinished(user_id: i32, pool: &PgPool) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select id, user_id, record_id, state as "state: _", file_path, info_path,
+                bytes, duration, started_at, finished_at, created_at
+            from recordings
+            where user_id = $1 and state not in ('stopped', 'error')
+            order by created_at desc
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn all(user_id: i32, pool: &PgPool) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select id, user_id, record_id, state as "state: _", file_path, info_path,
+                bytes, duration, started_at, finished_at, created_at
+            from recordings
+            where user_id = $1
+            order by created_at desc
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// OME forgets recordings once they are finished, also when the stream
+    /// ended on its own.
+    async fn finish(&self, pool: &PgPool) -> Result<Self> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            update recordings
+            set state = 'stopped', finished_at = coalesce(finished_at, now())
+            where id = $1
+            returning id, user_id, record_id, state as "state: _", file_path, info_path,
+                bytes, duration, started_at, finished_at, created_at
+            "#,
+            self.id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Starts recording the user's stream, `None` if it isn't live.
+    pub async fn start(user: &User, ome: &Ome, pool: &PgPool) -> anyhow::Result<Option<Self>> {
+        let id = Ulid::new().to_string();
+        match ome.start_record(&id, &user.username).await? {
+            Some(record) => Ok(Some(Self::save(user.id, &record, pool).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn stop(&self, ome: &Ome, pool: &PgPool) -> anyhow::Result<Self> {
+        match ome.stop_record(&self.record_id).await? {
+            Some(record) => Ok(Self::save(self.user_id, &record, pool).await?),
+            None => Ok(self.finish(pool).await?),
+        }
+    }
+
+    /// Fetches the state of the user's unfinished recordings from OME.
+    pub async fn sync(user_id: i32, ome: &Ome, pool: &PgPool) -> anyhow::Result<()> {
+        for recording in Self::unfinished(user_id, pool).await? {
+            match ome.record(&recording.record_id).await? {
+                Some(record) => Self::save(user_id, &record, pool).await?,
+                None => recording.finish(pool).await?,
+            };
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgPool, Result};
+
+/// One ingest into OvenMediaEngine, from the admission webhook allowing it
+/// until OME reports it closed.
+#[derive(Debug, Serialize)]
+pub struct StreamSession {
+    pub id: i64,
+    pub user_id: i32,
+    pub protocol: String,
+    pub address: String,
+    pub port: i32,
+    pub user_agent: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+/// Where an ingest comes from, as reported by OME.
+#[derive(Debug)]
+pub struct Ingest<'a> {
+    pub protocol: &'a str,
+    pub address: &'a str,
+    pub port: u16,
+    pub user_agent: Option<&'a str>,
+}
+
+impl StreamSession {
+    /// Records a new session. Sessions still open from the same address
+    /// never got closed by OME, so they end here. Other ingests of the user
+    /// stay open.
+    pub async fn start(
+        user_id: i32,
+        ingest: &Ingest<'_>,
+        started_at: DateTime<Utc>,
+        pool: &PgPool,
+    ) -> Result<Self> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            with stale as (
+                update stream_sessions
+                set ended_at = $6
+                where user_id = $1 and address = $3 and ended_at is null
+            )
+            insert into stream_sessions (user_id, protocol, address, port, user_agent, started_at)
+            values ($1, $2, $3, $4, $5, $6)
+            returning id, user_id, protocol, address, port, user_agent, started_at, ended_at
+            "#,
+            user_id,
+            ingest.protocol,
+            ingest.address,
+            i32::from(ingest.port),
+            ingest.user_agent,
+            started_at
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Closes the user's open session from the same client.
+    pub async fn end(
+        user_id: i32,
+        ingest: &Ingest<'_>,
+        ended_at: DateTime<Utc>,
+        pool: &PgPool,
+    ) -> Result<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            update stream_sessions
+            set ended_at = $5
+            where id = (
+                select id from stream_sessions
+                where user_id = $1
+                    and protocol = $2
+                    and address = $3
+                    and port = $4
+                    and ended_at is null
+                order by started_at desc
+                limit 1
+            )
+            returning id, user_id, protocol, address, port, user_agent, started_at, ended_at
+            "#,
+            user_id,
+            ingest.protocol,
+            ingest.address,
+            i32::from(ingest.port),
+            ended_at
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Whether the user has any ingest open.
+    pub async fn is_live(user_id: i32, pool: &PgPool) -> Result<bool> {
+        let row = sqlx::query!(
+            r#"--sql
+            select exists(
+                select 1 from stream_sessions where user_id = $1 and ended_at is null
+            ) as "live!"
+            "#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(row.live)
+    }
+}
+
+/// A stream that is live right now, for the directory.
+#[derive(Debug, Serialize)]
+pub struct LiveStream {
+    pub username: String,
+    pub name: Option<String>,
+    pub protocol: String,
+    pub started_at: DateTime<Utc>,
+}
+
+impl LiveStream {
+    /// Streams with an open session, filtered by `search` in username or
+    /// title and by `protocol`. Non-public streams are only shown if
+    /// `show_all` is set.
+    pub async fn all(
+        show_all: bool,
+        search: Option<&str>,
+        protocol: Option<&str>,
+        pool: &PgPool,
+    ) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select
+                u.username,
+                o.name,
+                s.protocol,
+                s.started_at
+            from stream_sessions s
+            join users u on u.id = s.user_id
+            join options o on o.user_id = s.user_id
+            where s.ended_at is null
+                and not u.hidden
+                and ($1 or o.public)
+                and ($2::text is null or u.username ilike '%' || $2 || '%' or o.name ilike '%' || $2 || '%')
+                and ($3::text is null or s.protocol = $3)
+            "#,
+            show_all,
+            search,
+            protocol
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Live streams the user follows, most recently started first.
+    pub async fn followed(user_id: i32, pool: &PgPool) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"--sql
+            select
+                u.username,
+                o.name,
+                s.protocol,
+                s.started_at
+            from follows f
+            join stream_sessions s on s.user_id = f.streamer_id
+            join users u on u.id = f.streamer_id
+            join options o on o.user_id = f.streamer_id
+            where f.follower_id = $1
+                and s.ended_at is null
+                and not u.hidden
+            order by s.started_at desc
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingest(address: &str, port: u16) -> Ingest<'_> {
+        Ingest {
+            protocol: "rtmp",
+            address,
+            port,
+            user_agent: None,
+        }
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn live_until_the_last_ingest_closes(pool: PgPool) {
+        let user_id: i32 = sqlx::query_scalar(
+            "insert into users (username, password) values ('alice', '') returning id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let now = Utc::now();
+        let first = StreamSession::start(user_id, &ingest("1.1.1.1", 1000), now, &pool)
+            .await
+            .unwrap();
+        StreamSession::start(user_id, &ingest("2.2.2.2", 2000), now, &pool)
+            .await
+            .unwrap();
+        let ended = StreamSession::end(user_id, &ingest("1.1.1.1", 1000), now, &pool)
+            .await
+            .unwrap();
+        assert_eq!(ended.map(|s| s.id), Some(first.id));
+        assert!(StreamSession::is_live(user_id, &pool).await.unwrap());
+
+        // Reconnecting from the same address ends the session OME never closed
+        StreamSession::start(user_id, &ingest("2.2.2.2", 2001), now, &pool)
+            .await
+            .unwrap();
+        StreamSession::end(user_id, &ingest("2.2.2.2", 2001), now, &pool)
+            .await
+            .unwrap();
+        assert!(!StreamSession::is_live(user_id, &pool).await.unwrap());
+    }
+}
@@ -1,11 +1,110 @@
+use std::cmp::Reverse;
+
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
     routing::get,
-    Json, Router,
+    Extension, Json, Router,
 };
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use sqlx::PgPool;
 
-use crate::{error::OvenauthError, options::PublicOptions};
+use crate::{
+    error::OvenauthError,
+    feed,
+    ome::Ome,
+    options::{PublicOptions, StreamOptions},
+    session::LiveStream,
+    user::User,
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DirectorySort {
+    /// Most viewers first
+    #[default]
+    Viewers,
+    /// Most recently started first
+    Started,
+    /// By title, falling back to the username
+    Title,
+}
+
+#[derive(Debug, Deserialize)]
+struct DirectoryQuery {
+    #[serde(default)]
+    sort: DirectorySort,
+    /// Part of the username or title
+    q: Option<String>,
+    protocol: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DirectoryEntry {
+    #[serde(flatten)]
+    stream: LiveStream,
+    /// Watching according to OME, 0 if it can't tell
+    viewers: u64,
+}
+
+async fn directory(
+    Query(query): Query<DirectoryQuery>,
+    State(pool): State<PgPool>,
+    Extension(ome): Extension<Ome>,
+    user: Option<Extension<User>>,
+) -> Result<Json<Value>, OvenauthError> {
+    let streams = LiveStream::all(
+        user.is_some(),
+        query.q.as_deref(),
+        query.protocol.as_deref(),
+        &pool,
+    )
+    .await?;
+    let stats = join_all(streams.iter().map(|s| ome.stream_stats(&s.username))).await;
+    let mut streams: Vec<_> = streams
+        .into_iter()
+        .zip(stats)
+        .map(|(stream, stats)| {
+            let viewers = match stats {
+                Ok(stats) => stats.map_or(0, |stats| stats.viewers),
+                Err(e) => {
+                    tracing::warn!(%e, stream = stream.username, "Failed to fetch stream stats");
+                    0
+                }
+            };
+            DirectoryEntry { stream, viewers }
+        })
+        .collect();
+    match query.sort {
+        DirectorySort::Viewers => streams.sort_by_key(|s| Reverse(s.viewers)),
+        DirectorySort::Started => streams.sort_by_key(|s| Reverse(s.stream.started_at)),
+        DirectorySort::Title => streams.sort_by_cached_key(|s| {
+            s.stream
+                .name
+                .as_deref()
+                .unwrap_or(&s.stream.username)
+                .to_lowercase()
+        }),
+    }
+    Ok(Json(json!({ "streams": streams })))
+}
+
+/// Rotates the user's stream key and cuts off the ingest that is using it.
+/// Returns whether the stream was live.
+pub async fn stop(user: &User, pool: &PgPool, ome: &Ome) -> anyhow::Result<(bool, StreamOptions)> {
+    // Rotate first, so the old key can't reconnect
+    let options = StreamOptions::rotate_token(user.id, pool).await?;
+    let stopped = ome.stop_stream(&user.username).await?;
+    tracing::info!(
+        user = user.username,
+        stopped,
+        "Stopped stream and rotated key"
+    );
+    Ok((stopped, options))
+}
 
 async fn stream_options(
     Path(stream): Path<String>,
@@ -14,6 +113,23 @@ async fn stream_options(
     Ok(Json(PublicOptions::from_username(&stream, &pool).await?))
 }
 
+async fn stream_stats(
+    Path(stream): Path<String>,
+    Extension(ome): Extension<Ome>,
+    State(pool): State<PgPool>,
+) -> Result<Response, OvenauthError> {
+    // Only ever ask OME about streams that can exist
+    let user = User::from_username(&stream, &pool).await?;
+    match ome.stream_stats(&user.username).await? {
+        Some(stats) => Ok(Json(json!({ "stats": stats })).into_response()),
+        None => Ok((StatusCode::NOT_FOUND, "Stream is offline").into_response()),
+    }
+}
+
 pub fn routes() -> Router<PgPool> {
-    Router::new().route("/:stream", get(stream_options))
+    Router::new()
+        .route("/", get(directory))
+        .route("/:stream", get(stream_options))
+        .route("/:stream/stats", get(stream_stats))
+        .merge(feed::routes())
 }
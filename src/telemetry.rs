@@ -0,0 +1,188 @@
+use std::{
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header::AUTHORIZATION, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Router,
+};
+use metrics::{gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use ring::constant_time::verify_slices_are_equal;
+use sqlx::PgPool;
+use tracing::Span;
+use tracing_subscriber::{prelude::*, EnvFilter, Layer};
+
+use crate::chat::Chat;
+
+/// Histograms are only rendered once upkeep ran
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const DEFAULT_FILTER: &str = "ovenauth=info,tower_http=info,axum::rejection=trace";
+const REQUEST_ID: &str = "x-request-id";
+
+/// Logs according to `RUST_LOG` in the `LOG_FORMAT` (`compact`, `pretty` or
+/// `json`). `TOKIO_CONSOLE` serves tokio-console and
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` exports spans to an OTLP collector.
+pub fn setup_tracing() -> anyhow::Result<()> {
+    let filter = || EnvFilter::try_from_default_env().unwrap_or_else(|_| DEFAULT_FILTER.into());
+    let fmt = tracing_subscriber::fmt::layer().with_target(false);
+    let fmt = match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => fmt.json().boxed(),
+        Ok("pretty") => fmt.pretty().boxed(),
+        _ => fmt.compact().boxed(),
+    };
+    // Has its own filter for the task instrumentation it needs
+    let console = matches!(env::var("TOKIO_CONSOLE").as_deref(), Ok("1" | "true"))
+        .then(console_subscriber::spawn);
+    let otlp = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        // The exporter reads the endpoint on its own
+        Ok(_) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+                .with_trace_config(
+                    trace::config()
+                        .with_resource(Resource::new([KeyValue::new("service.name", "ovenauth")])),
+                )
+                .install_batch(runtime::Tokio)
+                .context("Failed to set up OTLP exporter")?;
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(filter()),
+            )
+        }
+        Err(_) => None,
+    };
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(filter()))
+        .with(console)
+        .with(otlp)
+        .init();
+    Ok(())
+}
+
+/// Sends the spans that are still buffered.
+pub fn shutdown_tracing() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// The id the request came with or was given by `SetRequestIdLayer`.
+pub fn request_id(headers: &HeaderMap) -> &str {
+    headers
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default()
+}
+
+pub fn request_span<B>(req: &Request<B>) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        request_id = request_id(req.headers()),
+    )
+}
+
+/// Installs the global recorder every `metrics::` macro records to.
+pub fn install() -> anyhow::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("ovenauth_http_request_duration_seconds".to_string()),
+            &LATENCY_BUCKETS,
+        )?
+        .install_recorder()?;
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+    Ok(handle)
+}
+
+/// Records the latency of every request by its route, not its path, so
+/// usernames don't end up as labels.
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |p| p.as_str().to_string());
+    let method = req.method().to_string();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    histogram!(
+        "ovenauth_http_request_duration_seconds",
+        "method" => method,
+        "route" => route,
+        "status" => res.status().as_str().to_string()
+    )
+    .record(start.elapsed());
+    res
+}
+
+/// What scrapers send as bearer token.
+#[derive(Debug, Clone)]
+struct MetricsToken(Arc<str>);
+
+impl MetricsToken {
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                verify_slices_are_equal(token.as_bytes(), self.0.as_bytes()).is_ok()
+            })
+    }
+}
+
+// ROUTES
+async fn metrics(
+    headers: HeaderMap,
+    Extension(token): Extension<MetricsToken>,
+    Extension(handle): Extension<PrometheusHandle>,
+    Extension(chat): Extension<Chat>,
+    State(pool): State<PgPool>,
+) -> Response {
+    if !token.matches(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let (rooms, connections) = chat.local_counts();
+    gauge!("ovenauth_chat_rooms").set(rooms as f64);
+    gauge!("ovenauth_chat_connections").set(connections as f64);
+    let idle = pool.num_idle();
+    gauge!("ovenauth_db_connections", "state" => "idle").set(idle as f64);
+    gauge!("ovenauth_db_connections", "state" => "active")
+        .set(pool.size().saturating_sub(idle as u32) as f64);
+    gauge!("ovenauth_db_max_connections").set(pool.options().get_max_connections() as f64);
+    handle.render().into_response()
+}
+
+/// Serves `/metrics` to scrapers sending the token in `METRICS_TOKEN`, and
+/// nothing without one.
+pub fn routes(handle: PrometheusHandle) -> Router<PgPool> {
+    let Some(token) = env::var("METRICS_TOKEN").ok().filter(|t| !t.is_empty()) else {
+        tracing::warn!("METRICS_TOKEN is not set, not serving metrics");
+        return Router::new();
+    };
+    Router::new()
+        .route("/metrics", get(metrics))
+        .layer(Extension(MetricsToken(token.into())))
+        .layer(Extension(handle))
+}
@@ -18,15 +18,19 @@ use serde_json::json;
 use sqlx::{postgres::PgRow, FromRow, PgPool, Row};
 
 use crate::{
+    audit::{self, AuditEvent, Source},
     error::OvenauthError,
+    follow,
+    hooks::{self, Event, Scope},
+    notifier,
+    ome::Ome,
     options::{StreamOptions, UpdateStreamOptions},
+    push,
+    recording::Recording,
+    session::Ingest,
+    stream,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct UserWrapper<T: std::fmt::Debug + Serialize> {
-    user: T,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginCredentials {
     pub username: String,
@@ -48,6 +52,9 @@ pub struct User {
     #[serde(skip)]
     pub password: SecretString,
     pub hidden: bool,
+    /// Only the user themselves gets to see this, see `/me`
+    #[serde(skip)]
+    pub admin: bool,
 }
 
 impl<'r> FromRow<'r, PgRow> for User {
@@ -57,6 +64,7 @@ impl<'r> FromRow<'r, PgRow> for User {
             username: row.try_get("username")?,
             password: SecretString::from_str(row.try_get("password")?).expect("Infallible"),
             hidden: row.try_get("hidden")?,
+            admin: row.try_get("admin")?,
         })
     }
 }
@@ -65,7 +73,7 @@ impl User {
     pub async fn from_token(token: &str, pool: &PgPool) -> Result<User> {
         let user = sqlx::query_as!(
             User,
-            "select u.username, u.id, u.password, u.hidden from users u, options o where u.id = o.user_id and o.token = $1",
+            "select u.username, u.id, u.password, u.hidden, u.admin from users u, options o where u.id = o.user_id and o.token = $1",
             token
         )
         .fetch_one(pool)
@@ -77,14 +85,14 @@ impl User {
     pub async fn from_creds(creds: &LoginCredentials, db: &PgPool) -> Result<User> {
         let user = sqlx::query_as!(
             User,
-            "select id, username, password, hidden from users where username = $1",
+            "select id, username, password, hidden, admin from users where username = $1",
             &creds.username
         )
         .fetch_one(db)
         .await?;
 
         let verified =
-            argon2::verify_encoded(&user.password.expose_secret(), creds.password.as_bytes())?;
+            argon2::verify_encoded(user.password.expose_secret(), creds.password.as_bytes())?;
 
         if verified {
             Ok(user)
@@ -100,7 +108,7 @@ impl User {
 
         let user = sqlx::query_as!(
             User,
-            "insert into users (username, password) values ($1, $2) returning id, username, password, hidden",
+            "insert into users (username, password) values ($1, $2) returning id, username, password, hidden, admin",
             &creds.username,
             &password
         )
@@ -112,6 +120,40 @@ impl User {
         Ok(user)
     }
 
+    pub async fn from_username(username: &str, db: &PgPool) -> sqlx::Result<User> {
+        sqlx::query_as!(
+            User,
+            "select id, username, password, hidden, admin from users where username = $1",
+            username
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    /// The user whose open session the ingest is, regardless of the key it
+    /// was started with.
+    pub async fn from_ingest(ingest: &Ingest<'_>, db: &PgPool) -> sqlx::Result<Option<User>> {
+        sqlx::query_as!(
+            User,
+            r#"--sql
+            select u.id, u.username, u.password, u.hidden, u.admin
+            from stream_sessions s
+            join users u on u.id = s.user_id
+            where s.protocol = $1
+                and s.address = $2
+                and s.port = $3
+                and s.ended_at is null
+            order by s.started_at desc
+            limit 1
+            "#,
+            ingest.protocol,
+            ingest.address,
+            i32::from(ingest.port)
+        )
+        .fetch_optional(db)
+        .await
+    }
+
     pub async fn all(db: &PgPool, show_all: bool) -> Result<Vec<User>> {
         let users = sqlx::query_as!(
             User,
@@ -144,6 +186,7 @@ pub type AuthContext = axum_login::extractors::AuthContext<i32, User, PostgresSt
 
 async fn register(
     mut auth: AuthContext,
+    source: Source,
     State(db): State<PgPool>,
     Json(creds): Json<RegisterCreds>,
 ) -> Result<Response, OvenauthError> {
@@ -154,6 +197,23 @@ async fn register(
     }
     let user = User::create_from_creds(&creds, &db).await?;
     auth.login(&user).await?;
+    audit::record(
+        Some(user.id),
+        Some(&user.username),
+        AuditEvent::Registered,
+        &source,
+        json!({}),
+        &db,
+    )
+    .await;
+    hooks::emit(
+        &db,
+        &user.username,
+        Event::UserRegistered {
+            username: user.username.clone(),
+        },
+    )
+    .await;
     Ok(Json(json!({ "user": user })).into_response())
 }
 
@@ -162,11 +222,39 @@ pub async fn logout(mut auth: AuthContext) -> impl IntoResponse {
 }
 async fn login(
     mut auth: AuthContext,
+    source: Source,
     State(db): State<PgPool>,
     Json(creds): Json<LoginCredentials>,
 ) -> Result<impl IntoResponse, OvenauthError> {
-    let user = User::from_creds(&creds, &db).await?;
+    let user = match User::from_creds(&creds, &db).await {
+        Ok(user) => user,
+        Err(e) => {
+            metrics::counter!("ovenauth_logins_total", "result" => "failure").increment(1);
+            // Shows up in the log of the account someone tried to get into
+            let target = User::from_username(&creds.username, &db).await.ok();
+            audit::record(
+                target.map(|user| user.id),
+                Some(&creds.username),
+                AuditEvent::LoginFailed,
+                &source,
+                json!({}),
+                &db,
+            )
+            .await;
+            return Err(e.into());
+        }
+    };
     auth.login(&user).await?;
+    metrics::counter!("ovenauth_logins_total", "result" => "success").increment(1);
+    audit::record(
+        Some(user.id),
+        Some(&user.username),
+        AuditEvent::Login,
+        &source,
+        json!({}),
+        &db,
+    )
+    .await;
     Ok(Json(json!({ "user": user })))
 }
 
@@ -179,7 +267,9 @@ async fn index(
 }
 
 async fn me(Extension(user): Extension<User>) -> impl IntoResponse {
-    Json(json!({ "user": user }))
+    let mut body = json!({ "user": user });
+    body["user"]["admin"] = json!(user.admin);
+    Json(body)
 }
 
 async fn options(
@@ -192,19 +282,114 @@ async fn options(
 
 async fn update_options(
     Extension(user): Extension<User>,
+    source: Source,
     State(db): State<PgPool>,
     Json(options): Json<UpdateStreamOptions>,
 ) -> Result<impl IntoResponse, OvenauthError> {
-    Ok(Json(options.update(user.id, &db).await?))
+    let rotated = options.rotates_token();
+    let options = options.update(user.id, &db).await?;
+    if rotated {
+        audit::record(
+            Some(user.id),
+            Some(&user.username),
+            AuditEvent::KeyRotated,
+            &source,
+            json!({ "via": "options" }),
+            &db,
+        )
+        .await;
+    }
+    let mut public = json!(options);
+    if let Some(public) = public.as_object_mut() {
+        public.remove("token");
+    }
+    hooks::emit(
+        &db,
+        &user.username,
+        Event::OptionsUpdated {
+            username: user.username.clone(),
+            options: public,
+        },
+    )
+    .await;
+    Ok(Json(options))
+}
+
+async fn stop_stream(
+    Extension(user): Extension<User>,
+    Extension(ome): Extension<Ome>,
+    source: Source,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let (stopped, options) = stream::stop(&user, &db, &ome).await?;
+    audit::record(
+        Some(user.id),
+        Some(&user.username),
+        AuditEvent::KeyRotated,
+        &source,
+        json!({ "via": "stop", "stopped": stopped }),
+        &db,
+    )
+    .await;
+    Ok(Json(json!({ "stopped": stopped, "options": options })))
+}
+
+async fn start_recording(
+    Extension(user): Extension<User>,
+    Extension(ome): Extension<Ome>,
+    State(db): State<PgPool>,
+) -> Result<Response, OvenauthError> {
+    Recording::sync(user.id, &ome, &db).await?;
+    if !Recording::unfinished(user.id, &db).await?.is_empty() {
+        return Ok((StatusCode::CONFLICT, "Already recording").into_response());
+    }
+    match Recording::start(&user, &ome, &db).await? {
+        Some(recording) => Ok(Json(json!({ "recording": recording })).into_response()),
+        None => Ok((StatusCode::CONFLICT, "Stream is offline").into_response()),
+    }
+}
+
+async fn stop_recording(
+    Extension(user): Extension<User>,
+    Extension(ome): Extension<Ome>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    let mut stopped = Vec::new();
+    for recording in Recording::unfinished(user.id, &db).await? {
+        stopped.push(recording.stop(&ome, &db).await?);
+    }
+    Ok(Json(json!({ "recordings": stopped })))
+}
+
+async fn recordings(
+    Extension(user): Extension<User>,
+    Extension(ome): Extension<Ome>,
+    State(db): State<PgPool>,
+) -> Result<impl IntoResponse, OvenauthError> {
+    if let Err(e) = Recording::sync(user.id, &ome, &db).await {
+        tracing::warn!(%e, user = user.username, "Failed to sync recordings");
+    }
+    let recordings = Recording::all(user.id, &db).await?;
+    Ok(Json(json!({ "recordings": recordings })))
 }
 
 pub fn routes() -> Router<PgPool> {
     Router::new()
         .route("/options", get(options).put(update_options))
+        .route("/stream/stop", post(stop_stream))
+        .route("/recording/start", post(start_recording))
+        .route("/recording/stop", post(stop_recording))
+        .route("/recordings", get(recordings))
         .route("/me", get(me))
         .route("/logout", post(logout))
         .route_layer(RequireAuthorizationLayer::<i32, User>::login())
         .route("/users", get(index))
         .route("/login", post(login))
         .route("/register", post(register))
+        .nest("/notifications", notifier::routes())
+        .nest("/follows", follow::routes())
+        .nest("/following", follow::following_routes())
+        .nest("/push", push::routes())
+        .nest("/hooks", hooks::routes(Scope::User))
+        .nest("/audit", audit::routes())
 }
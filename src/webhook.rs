@@ -1,10 +1,31 @@
-use axum::{extract::State, response::IntoResponse, routing::post, Json, Router};
+use std::time::Duration;
+
+use axum::{extract::State, response::IntoResponse, routing::post, Extension, Json, Router};
 use chrono::Utc;
 use serde::{de::Visitor, Deserialize, Serialize};
+use serde_json::json;
 use sqlx::PgPool;
+use tracing::Instrument;
 use url::Url;
 
-use crate::user::User;
+use crate::{
+    audit::{self, AuditEvent, Source},
+    chat::Chat,
+    feed::{self, StreamEvent},
+    health::Health,
+    hooks::{self, Event},
+    notifier::Notifier,
+    ome::Ome,
+    options::StreamOptions,
+    recording::Recording,
+    session::{Ingest, StreamSession},
+    user::User,
+};
+
+/// OME only creates the stream after we admitted it, so recording it has to
+/// wait a bit.
+const RECORD_DELAY: Duration = Duration::from_secs(2);
+const RECORD_ATTEMPTS: usize = 5;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
@@ -23,6 +44,8 @@ struct Client {
 struct Request {
     direction: Direction,
     protocol: Protocol,
+    #[serde(default)]
+    status: Status,
     url: String,
     time: chrono::DateTime<Utc>,
     new_url: Option<String>,
@@ -35,6 +58,15 @@ enum Direction {
     Outgoing,
 }
 
+/// OME calls the webhook again with `closing` once the session ends.
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    #[default]
+    Opening,
+    Closing,
+}
+
 #[derive(Debug, Serialize)]
 enum Protocol {
     WebRTC,
@@ -44,6 +76,18 @@ enum Protocol {
     Thumbnail,
 }
 
+impl Protocol {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::WebRTC => "webrtc",
+            Self::Rtmp => "rtmp",
+            Self::Srt => "srt",
+            Self::Llhls => "llhls",
+            Self::Thumbnail => "thumbnail",
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for Protocol {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -113,6 +157,14 @@ impl WebhookResponse {
     fn denied(reason: String) -> Self {
         Self::new(false, None, None, Some(reason))
     }
+
+    fn decision(&self) -> &'static str {
+        match (self.allowed, &self.new_url) {
+            (false, _) => "denied",
+            (true, None) => "allowed",
+            (true, Some(_)) => "redirect",
+        }
+    }
 }
 
 impl IntoResponse for WebhookResponse {
@@ -121,8 +173,96 @@ impl IntoResponse for WebhookResponse {
     }
 }
 
+async fn record_by_default(user: User, ome: Ome, db: PgPool) {
+    match StreamOptions::records_by_default(user.id, &db).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            tracing::error!(%e, user = user.username, "Failed to read recording option");
+            return;
+        }
+    }
+    for _ in 0..RECORD_ATTEMPTS {
+        tokio::time::sleep(RECORD_DELAY).await;
+        match Recording::start(&user, &ome, &db).await {
+            Ok(Some(recording)) => {
+                tracing::info!(
+                    user = user.username,
+                    recording.record_id,
+                    "Started recording"
+                );
+                return;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!(%e, user = user.username, "Failed to start recording");
+                return;
+            }
+        }
+    }
+    tracing::warn!(
+        user = user.username,
+        "Stream never showed up to be recorded"
+    );
+}
+
+async fn notify_live(user: User, notifier: Notifier) {
+    if let Err(e) = notifier.live(&user).await {
+        tracing::error!(%e, user = user.username, "Failed to send live notifications");
+    }
+}
+
+/// Picks up the final size and length once OME is done writing.
+async fn sync_recordings(user: User, ome: Ome, db: PgPool) {
+    tokio::time::sleep(RECORD_DELAY).await;
+    if let Err(e) = Recording::sync(user.id, &ome, &db).await {
+        tracing::error!(%e, user = user.username, "Failed to sync recordings");
+    }
+}
+
 // TODO: verify X-OME-Signature
-async fn webhook(State(db): State<PgPool>, Json(body): Json<Config>) -> WebhookResponse {
+async fn webhook(
+    State(db): State<PgPool>,
+    Extension(chat): Extension<Chat>,
+    Extension(ome): Extension<Ome>,
+    Extension(notifier): Extension<Notifier>,
+    Extension(health): Extension<Health>,
+    Json(body): Json<Config>,
+) -> WebhookResponse {
+    let protocol = body.request.protocol.as_str();
+    let source = Source {
+        ip: Some(body.client.address.clone()),
+        user_agent: body.client.user_agent.clone(),
+    };
+    let res = admit(db.clone(), chat, ome, notifier, health, body).await;
+    if !res.allowed {
+        audit::record(
+            None,
+            None,
+            AuditEvent::WebhookDenied,
+            &source,
+            json!({ "protocol": protocol, "reason": res.reason }),
+            &db,
+        )
+        .await;
+    }
+    metrics::counter!(
+        "ovenauth_webhook_decisions_total",
+        "decision" => res.decision(),
+        "protocol" => protocol
+    )
+    .increment(1);
+    res
+}
+
+async fn admit(
+    db: PgPool,
+    chat: Chat,
+    ome: Ome,
+    notifier: Notifier,
+    health: Health,
+    body: Config,
+) -> WebhookResponse {
     if let Direction::Outgoing = body.request.direction {
         // TODO Implement correct redirects
         return WebhookResponse::allowed();
@@ -148,15 +288,107 @@ async fn webhook(State(db): State<PgPool>, Json(body): Json<Config>) -> WebhookR
     }
 
     let token = creds[1];
+    let live = matches!(body.request.status, Status::Opening);
+    let ingest = Ingest {
+        protocol: body.request.protocol.as_str(),
+        address: &body.client.address,
+        port: body.client.port,
+        user_agent: body.client.user_agent.as_deref(),
+    };
 
-    let user = match User::from_token(token, &db).await {
-        Ok(user) => user,
-        Err(e) => {
-            tracing::error!("{e}");
-            return WebhookResponse::denied(format!("{e}"));
+    // Stopping the stream or rotating the key while live leaves OME closing
+    // the ingest with a key that is gone, so it is found by its session
+    let open = if live {
+        Ok(None)
+    } else {
+        User::from_ingest(&ingest, &db).await
+    };
+    let user = match open {
+        Ok(Some(user)) => user,
+        open => {
+            if let Err(e) = open {
+                tracing::error!(%e, "Failed to look up stream session");
+            }
+            match User::from_token(token, &db).await {
+                Ok(user) => user,
+                Err(e) => {
+                    tracing::error!("{e}");
+                    return WebhookResponse::denied(format!("{e}"));
+                }
+            }
         }
     };
-    url.set_path(&format!("app/{}", user.username));
+    let session = if live {
+        StreamSession::start(user.id, &ingest, body.request.time, &db)
+            .await
+            .map(Some)
+    } else {
+        StreamSession::end(user.id, &ingest, body.request.time, &db).await
+    };
+    match session {
+        Ok(Some(_)) => {}
+        Ok(None) => tracing::warn!(
+            user = user.username,
+            ?ingest,
+            "Closed unknown stream session"
+        ),
+        Err(e) => tracing::error!(%e, user = user.username, "Failed to record stream session"),
+    }
+    if !live {
+        match StreamSession::is_live(user.id, &db).await {
+            Ok(true) => {
+                tracing::info!(
+                    user = user.username,
+                    ?ingest,
+                    "Another ingest is still open"
+                );
+                return WebhookResponse::allowed();
+            }
+            Ok(false) => {}
+            Err(e) => {
+                tracing::error!(%e, user = user.username, "Failed to look up stream sessions")
+            }
+        }
+    }
+    chat.set_live(&user.username, live).await;
+    let username = user.username.clone();
+    let protocol = ingest.protocol.to_string();
+    let event = if live {
+        Event::StreamStarted {
+            username,
+            protocol,
+            started_at: body.request.time,
+        }
+    } else {
+        Event::StreamEnded {
+            username,
+            protocol,
+            ended_at: body.request.time,
+        }
+    };
+    hooks::emit(&db, &user.username, event).await;
+    let event = if live {
+        StreamEvent::Online {
+            protocol: ingest.protocol.to_string(),
+            started_at: body.request.time,
+        }
+    } else {
+        StreamEvent::Offline {
+            ended_at: body.request.time,
+        }
+    };
+    if let Err(e) = feed::publish(user.id, &event, &db).await {
+        tracing::error!(%e, user = user.username, "Failed to publish stream status");
+    }
+    if !live {
+        health.spawn(sync_recordings(user, ome, db).in_current_span());
+        // The response to closing is ignored
+        return WebhookResponse::allowed();
+    }
+    let username = user.username.clone();
+    health.spawn(notify_live(user.clone(), notifier).in_current_span());
+    health.spawn(record_by_default(user, ome, db).in_current_span());
+    url.set_path(&format!("app/{username}"));
     WebhookResponse::redirect(url.to_string())
 }
 